@@ -0,0 +1,31 @@
+// EchoKit dev boards: INMP441 microphone and MAX98357 amplifier on separate
+// I2S buses, ST7789 240x240 display on SPI.
+BoardConfig {
+    name: "echokit-boards",
+    button: 0,
+    codec: Codec::None,
+    audio: AudioPins::Split {
+        mic_ws: 4,
+        mic_sck: 5,
+        mic_din: 6,
+        spk_bclk: 15,
+        spk_lrclk: 16,
+        spk_dout: 7,
+    },
+    display: DisplayConfig {
+        width: 240,
+        height: 240,
+        controller: DisplayController::St7789 {
+            mosi: 47,
+            clk: 21,
+            cs: 41,
+            dc: 40,
+            rst: 45,
+            pclk_hz: 40 * 1000 * 1000,
+            mirror_x: true,
+            mirror_y: false,
+            swap_xy: false,
+            invert_color: true,
+        },
+    },
+}
@@ -0,0 +1,18 @@
+// EchoKit box: ES8311 codec on one bidirectional I2S bus, 320x240 display
+// driven by the `hal_driver` component.
+BoardConfig {
+    name: "echokit-box",
+    button: 0,
+    codec: Codec::Es8311 { volume: 75 },
+    audio: AudioPins::Bidir {
+        bclk: 21,
+        ws: 13,
+        din: 47,
+        dout: 14,
+    },
+    display: DisplayConfig {
+        width: 320,
+        height: 240,
+        controller: DisplayController::HalDriver,
+    },
+}
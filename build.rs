@@ -1,6 +1,25 @@
 fn main() {
     embuild::espidf::sysenv::output();
 
+    // A custom board description, see `boards/` for the format.
+    println!("cargo:rustc-check-cfg=cfg(echokit_board_config)");
+    println!("cargo:rerun-if-env-changed=ECHOKIT_BOARD_CONFIG");
+    if let Ok(path) = std::env::var("ECHOKIT_BOARD_CONFIG") {
+        let path = std::fs::canonicalize(&path)
+            .unwrap_or_else(|e| panic!("ECHOKIT_BOARD_CONFIG={path}: {e}"));
+        println!("cargo:rerun-if-changed={}", path.display());
+        println!(
+            "cargo:rustc-env=ECHOKIT_BOARD_CONFIG_FILE={}",
+            path.display()
+        );
+        println!("cargo:rustc-cfg=echokit_board_config");
+    }
+
+    // Ed25519 key that firmware updates must be signed with.
+    check_key("ECHOKIT_OTA_PUBLIC_KEY");
+    // Fleet key for signing settings exports.
+    check_key("ECHOKIT_SETTINGS_KEY");
+
     slint_build::compile_with_config(
         "appwindow.slint",
         slint_build::CompilerConfiguration::new()
@@ -8,3 +27,14 @@ fn main() {
     )
     .unwrap();
 }
+
+/// A key given in the environment variable `name`, if set, must be 64 hex
+/// digits.
+fn check_key(name: &str) {
+    println!("cargo:rerun-if-env-changed={name}");
+    if let Ok(key) = std::env::var(name) {
+        if key.len() != 64 || !key.chars().all(|c| c.is_ascii_hexdigit()) {
+            panic!("{name} must be 64 hex digits, got {key:?}");
+        }
+    }
+}
@@ -3,10 +3,20 @@ use tokio_websockets::Message;
 
 use crate::{
     audio::{self, AudioData},
+    network::LinkState,
     protocol::ServerEvent,
     ws::Server,
 };
 
+/// Attempts to reopen the server connection after the link comes back.
+const RECONNECT_ATTEMPTS: u32 = 3;
+/// Server action that erases the settings and assets, see
+/// [`crate::settings::Store::factory_reset`].
+const FACTORY_RESET: &str = "factory_reset";
+/// How often the status bar is refreshed: the Wi-Fi signal, volume and
+/// battery.
+const STATUS_INTERVAL: std::time::Duration = std::time::Duration::from_secs(5);
+
 #[derive(Debug)]
 pub enum Event {
     Event(&'static str),
@@ -28,10 +38,37 @@ impl Event {
 
     pub const K1: &'static str = "k1";
     pub const K2: &'static str = "k2";
+
+    pub const LINK_UP: &'static str = "link_up";
+    pub const LINK_DOWN: &'static str = "link_down";
+    /// Time for the next background frame, see [`crate::ui::UI::tick`].
+    pub const FRAME: &'static str = "frame";
+    /// Time to refresh the status bar, see [`STATUS_INTERVAL`].
+    pub const STATUS: &'static str = "status";
 }
 
-async fn select_evt(evt_rx: &mut mpsc::Receiver<Event>, server: &mut Server) -> Option<Event> {
+async fn select_evt(
+    evt_rx: &mut mpsc::Receiver<Event>,
+    server: &mut Server,
+    link: &mut LinkState,
+    next_frame: Option<std::time::Instant>,
+    status_timer: &mut tokio::time::Interval,
+) -> Option<Event> {
+    // The server connection is left alone while the link is down.
+    let online = *link.borrow();
+    let frame_at = next_frame.map(tokio::time::Instant::from_std);
     tokio::select! {
+        _ = tokio::time::sleep_until(frame_at.unwrap_or_else(tokio::time::Instant::now)), if frame_at.is_some() => {
+            Some(Event::Event(Event::FRAME))
+        }
+        _ = status_timer.tick() => {
+            Some(Event::Event(Event::STATUS))
+        }
+        Ok(()) = link.changed() => {
+            let up = *link.borrow_and_update();
+            log::info!("Wifi link {}", if up { "up" } else { "down" });
+            Some(Event::Event(if up { Event::LINK_UP } else { Event::LINK_DOWN }))
+        }
         Some(evt) = evt_rx.recv() => {
             match &evt {
                 Event::Event(_)=>{
@@ -49,7 +86,7 @@ async fn select_evt(evt_rx: &mut mpsc::Receiver<Event>, server: &mut Server) ->
             }
             Some(evt)
         }
-        Ok(msg) = server.recv() => {
+        Ok(msg) = server.recv(), if online => {
             match msg {
                 Event::ServerEvent(ServerEvent::AudioChunk { .. })=>{
                     log::info!("Received AudioChunk");
@@ -60,6 +97,9 @@ async fn select_evt(evt_rx: &mut mpsc::Receiver<Event>, server: &mut Server) ->
                 Event::ServerEvent(ServerEvent::BGChunk { .. })=>{
                     log::info!("Received BGChunk");
                 }
+                Event::ServerEvent(ServerEvent::OtaChunk { .. })=>{
+                    log::debug!("Received OtaChunk");
+                }
                 _=> {
                     log::info!("Received message: {:?}", msg);
                 }
@@ -76,6 +116,7 @@ async fn select_evt(evt_rx: &mut mpsc::Receiver<Event>, server: &mut Server) ->
 struct DownloadMetrics {
     start_time: std::time::Instant,
     data_size: usize,
+    byte_rate: f64,
     timeout_sec: u64,
 }
 
@@ -84,6 +125,7 @@ impl DownloadMetrics {
         Self {
             start_time: std::time::Instant::now() - std::time::Duration::from_secs(300),
             data_size: 0,
+            byte_rate: 2.0 * audio::SAMPLE_RATE as f64,
             timeout_sec: 30,
         }
     }
@@ -92,9 +134,11 @@ impl DownloadMetrics {
         self.start_time.elapsed().as_secs() > self.timeout_sec
     }
 
-    fn reset(&mut self) {
+    /// Starts measuring a download of 16-bit mono audio at `sample_rate`.
+    fn reset(&mut self, sample_rate: u32) {
         self.start_time = std::time::Instant::now();
         self.data_size = 0;
+        self.byte_rate = 2.0 * sample_rate as f64;
     }
 
     fn add_data(&mut self, size: usize) {
@@ -106,16 +150,91 @@ impl DownloadMetrics {
     }
 
     fn speed(&self) -> f64 {
-        self.elapsed().as_secs_f64() / ((self.data_size as f64) / 32000.0)
+        self.elapsed().as_secs_f64() / ((self.data_size as f64) / self.byte_rate)
     }
 }
 
+/// Roughly how much UTF-8 text is spoken per second, about the same for
+/// English letters as for CJK characters at three bytes each.
+const SPOKEN_BYTES_PER_SEC: f64 = 14.0;
+
+/// How often the text follows the playback while waiting for it to end.
+const SCROLL_INTERVAL: std::time::Duration = std::time::Duration::from_millis(250);
+
+/// How far the player is into the current sentence, to scroll its text along.
+struct Playback {
+    started: Option<std::time::Instant>,
+    data_size: usize,
+    byte_rate: f64,
+    /// Guess of the sentence length from its text, until all audio arrived.
+    estimate_sec: Option<f64>,
+}
+
+impl Playback {
+    fn new(text: &str, sample_rate: u32) -> Self {
+        Self {
+            started: None,
+            data_size: 0,
+            byte_rate: 2.0 * sample_rate as f64,
+            estimate_sec: Some(text.len() as f64 / SPOKEN_BYTES_PER_SEC),
+        }
+    }
+
+    fn add_data(&mut self, size: usize) {
+        self.data_size += size;
+    }
+
+    /// More text of the sentence arrived.
+    fn add_text(&mut self, text: &str) {
+        if let Some(estimate) = &mut self.estimate_sec {
+            *estimate += text.len() as f64 / SPOKEN_BYTES_PER_SEC;
+        }
+    }
+
+    /// The first audio went to the player.
+    fn start(&mut self) {
+        self.started.get_or_insert_with(std::time::Instant::now);
+    }
+
+    /// All audio arrived, its length is known.
+    fn end(&mut self) {
+        self.estimate_sec = None;
+    }
+
+    /// From 0 at the start of the sentence to 1 at its end.
+    fn position(&self) -> f32 {
+        let Some(started) = self.started else {
+            return 0.0;
+        };
+        let received = self.data_size as f64 / self.byte_rate;
+        let total = received.max(self.estimate_sec.unwrap_or(0.0));
+        if total <= 0.0 {
+            return 1.0;
+        }
+        (started.elapsed().as_secs_f64() / total).min(1.0) as f32
+    }
+}
+
+async fn ota_failed(
+    server: &mut Server,
+    gui: &mut crate::ui::UI,
+    e: anyhow::Error,
+) -> anyhow::Result<()> {
+    log::error!("Firmware update failed: {:?}", e);
+    gui.state = "Firmware update failed".to_string();
+    gui.text = e.to_string();
+    gui.display_flush().unwrap();
+    server.send(Message::text(format!("Error:Ota:{e}"))).await
+}
+
 // TODO: 按键打断
 // TODO: 超时不监听
 pub async fn main_work<'d>(
     mut server: Server,
     player_tx: audio::PlayerTx,
     mut evt_rx: mpsc::Receiver<Event>,
+    mut link: LinkState,
+    setting: crate::SharedSetting,
     backgroud_buffer: Option<&'d [u8]>,
 ) -> anyhow::Result<()> {
     #[derive(PartialEq, Eq)]
@@ -127,7 +246,19 @@ pub async fn main_work<'d>(
         Idle,
     }
 
-    let mut gui = crate::ui::UI::new(backgroud_buffer)?;
+    let mut gui = crate::ui::UI::new(backgroud_buffer.map(std::borrow::Cow::Borrowed))?;
+    gui.set_panels(setting.lock().unwrap().panels);
+    gui.set_theme(setting.lock().unwrap().theme);
+
+    // `main_work` starts with the server connected.
+    let mut status = crate::ui::Status {
+        wifi: crate::network::rssi(),
+        server: true,
+        volume: crate::board::volume(),
+        battery: crate::board::battery(),
+    };
+    gui.set_status(status)?;
+    let mut status_timer = tokio::time::interval(STATUS_INTERVAL);
 
     gui.state = "Idle".to_string();
     gui.display_flush().unwrap();
@@ -143,9 +274,87 @@ pub async fn main_work<'d>(
     let mut metrics = DownloadMetrics::new();
     let mut need_compute = true;
     let mut speed = 0.8;
-
-    while let Some(evt) = select_evt(&mut evt_rx, &mut server).await {
+    let mut playback = Playback::new("", audio::SAMPLE_RATE);
+
+    let mut ota: Option<crate::ota::Update> = None;
+    let mut ota_percent = 0;
+
+    // Reaching this point means the firmware works well enough to update
+    // itself again. Setup mode confirms it once BLE is up.
+    crate::ota::mark_valid();
+
+    while let Some(evt) = select_evt(
+        &mut evt_rx,
+        &mut server,
+        &mut link,
+        gui.next_frame(),
+        &mut status_timer,
+    )
+    .await
+    {
         match evt {
+            Event::Event(Event::FRAME) => {
+                if let Err(e) = gui.tick() {
+                    log::error!("Failed to draw background frame: {:?}", e);
+                }
+            }
+            Event::Event(Event::STATUS) => {
+                status.wifi = if *link.borrow() {
+                    crate::network::rssi()
+                } else {
+                    None
+                };
+                status.volume = crate::board::volume();
+                status.battery = crate::board::battery();
+                if let Err(e) = gui.set_status(status) {
+                    log::error!("Failed to draw status bar: {:?}", e);
+                }
+            }
+            Event::Event(Event::LINK_DOWN) => {
+                // Drop whatever was in flight, the microphone is ignored
+                // until the link and the server connection are back.
+                state = State::Idle;
+                submit_audio = 0.0;
+                audio_buffer.clear();
+                ota = None;
+                status.wifi = None;
+                status.server = false;
+                gui.set_status(status)?;
+                gui.state = "Wi-Fi disconnected".to_string();
+                gui.text = "Reconnecting...".to_string();
+                gui.display_flush().unwrap();
+            }
+            Event::Event(Event::LINK_UP) => {
+                gui.state = "Reconnecting to server...".to_string();
+                gui.text.clear();
+                gui.display_flush().unwrap();
+                let mut attempt = 0;
+                loop {
+                    match server.reconnect().await {
+                        Ok(()) => {
+                            status.wifi = crate::network::rssi();
+                            status.server = true;
+                            gui.set_status(status)?;
+                            gui.state = "Idle".to_string();
+                            gui.display_flush().unwrap();
+                            break;
+                        }
+                        // Dropped again, wait for the next LINK_UP.
+                        Err(e) if !*link.borrow() => {
+                            log::warn!("Failed to reconnect to server: {:?}", e);
+                            break;
+                        }
+                        Err(e) => {
+                            attempt += 1;
+                            log::warn!("Failed to reconnect to server: {:?}", e);
+                            if attempt >= RECONNECT_ATTEMPTS {
+                                return Err(e);
+                            }
+                            tokio::time::sleep(std::time::Duration::from_secs(2 << attempt)).await;
+                        }
+                    }
+                }
+            }
             Event::Event(Event::GAIA | Event::K0) => {
                 log::info!("Received event: gaia");
                 // gui.state = "gaia".to_string();
@@ -222,26 +431,95 @@ pub async fn main_work<'d>(
                 log::info!("Received ASR: {:?}", text);
                 gui.state = "ASR".to_string();
                 gui.text = text.trim().to_string();
-                gui.display_flush().unwrap();
+                // Keep the latest words in view.
+                gui.display_flush_scrolled(1.0).unwrap();
+            }
+            Event::ServerEvent(ServerEvent::PartialASR { text }) => {
+                log::debug!("Received partial ASR: {:?}", text);
+                gui.text = text.trim().to_string();
+                if let Err(e) = gui.scroll_text(1.0) {
+                    log::error!("Error scrolling text: {:?}", e);
+                }
+            }
+            Event::ServerEvent(ServerEvent::Action { action }) if action == FACTORY_RESET => {
+                let r = setting.lock().unwrap().factory_reset();
+                match r {
+                    Ok(()) => {
+                        gui.state = "Factory reset, restarting...".to_string();
+                        gui.text.clear();
+                        gui.display_flush().unwrap();
+                        server.send(Message::text("FactoryReset:Done")).await?;
+                        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                        unsafe { esp_idf_svc::sys::esp_restart() }
+                    }
+                    Err(e) => {
+                        log::error!("Factory reset failed: {:?}", e);
+                        server
+                            .send(Message::text(format!("Error:FactoryReset:{e}")))
+                            .await?;
+                    }
+                }
+            }
+            Event::ServerEvent(ServerEvent::Theme { theme }) => {
+                log::info!("Received theme {:?}", theme);
+                let r = setting.lock().unwrap().update_theme(theme);
+                match r {
+                    Ok(()) => {
+                        gui.set_panels(setting.lock().unwrap().panels);
+                        gui.set_theme(setting.lock().unwrap().theme);
+                        gui.display_flush().unwrap();
+                    }
+                    Err(e) => {
+                        log::error!("Failed to set theme: {:?}", e);
+                        server
+                            .send(Message::text(format!("Error:Theme:{e}")))
+                            .await?;
+                    }
+                }
             }
             Event::ServerEvent(ServerEvent::Action { action }) => {
                 log::info!("Received action");
                 gui.state = format!("Action: {}", action);
                 gui.display_flush().unwrap();
             }
-            Event::ServerEvent(ServerEvent::StartAudio { text }) => {
+            Event::ServerEvent(ServerEvent::StartAudio { text, sample_rate }) => {
+                let sample_rate = match sample_rate {
+                    Some(rate) if crate::resample::is_supported_rate(rate) => rate,
+                    Some(rate) => {
+                        log::warn!("Unsupported sample rate {}, assuming 16 kHz", rate);
+                        audio::SAMPLE_RATE
+                    }
+                    None => audio::SAMPLE_RATE,
+                };
                 if need_compute {
-                    metrics.reset();
+                    metrics.reset(sample_rate);
                 }
-                log::info!("Received audio start: {:?}", text);
+                log::info!("Received audio start: {:?} at {} Hz", text, sample_rate);
                 state = State::Speaking;
                 gui.state = format!("[{:.2}x]|Speaking...", speed);
                 gui.text = text.trim().to_string();
                 gui.display_flush().unwrap();
+                playback = Playback::new(&gui.text, sample_rate);
                 player_tx
-                    .send(AudioData::Start)
+                    .send(AudioData::Start(sample_rate))
                     .map_err(|e| anyhow::anyhow!("Error sending start: {e:?}"))?;
             }
+            Event::ServerEvent(ServerEvent::AudioText { text }) => {
+                log::debug!("Received audio text: {:?}", text);
+                if state != State::Speaking {
+                    log::warn!("Received audio text while not speaking");
+                    continue;
+                }
+                if gui.text.is_empty() {
+                    gui.text = text.trim_start().to_string();
+                } else {
+                    gui.text.push_str(&text);
+                }
+                playback.add_text(&text);
+                if let Err(e) = gui.scroll_text(playback.position()) {
+                    log::error!("Error scrolling text: {:?}", e);
+                }
+            }
             Event::ServerEvent(ServerEvent::AudioChunk { data }) => {
                 log::info!("Received audio chunk");
                 if state != State::Speaking {
@@ -252,8 +530,10 @@ pub async fn main_work<'d>(
                 if need_compute {
                     metrics.add_data(data.len());
                 }
+                playback.add_data(data.len());
 
                 if speed < 1.0 {
+                    playback.start();
                     if let Err(e) = player_tx.send(AudioData::Chunk(data)) {
                         log::error!("Error sending audio chunk: {:?}", e);
                         gui.state = "Error on audio chunk".to_string();
@@ -262,6 +542,9 @@ pub async fn main_work<'d>(
                 } else {
                     audio_buffer.extend_from_slice(&data);
                 }
+                if let Err(e) = gui.scroll_text(playback.position()) {
+                    log::error!("Error scrolling text: {:?}", e);
+                }
             }
             Event::ServerEvent(ServerEvent::EndAudio) => {
                 log::info!("Received audio end");
@@ -273,7 +556,9 @@ pub async fn main_work<'d>(
 
                 log::info!("Audio speed: {:.2}x", speed);
 
+                playback.end();
                 if speed > 1.0 && audio_buffer.len() > 0 {
+                    playback.start();
                     if let Err(e) = player_tx.send(AudioData::Chunk(audio_buffer)) {
                         log::error!("Error sending audio chunk: {:?}", e);
                         gui.state = "Error on audio chunk".to_string();
@@ -282,13 +567,31 @@ pub async fn main_work<'d>(
                     audio_buffer = Vec::with_capacity(8192);
                 }
 
-                let (tx, rx) = tokio::sync::oneshot::channel();
+                let (tx, mut rx) = tokio::sync::oneshot::channel();
                 if let Err(e) = player_tx.send(AudioData::End(tx)) {
                     log::error!("Error sending audio chunk: {:?}", e);
                     gui.state = "Error on audio chunk".to_string();
                     gui.display_flush().unwrap();
                 }
-                let _ = rx.await;
+                // Follow the playback with the text and the background
+                // until the sentence is over.
+                let mut scroll_timer = tokio::time::interval(SCROLL_INTERVAL);
+                loop {
+                    let frame_at = gui.next_frame().map(tokio::time::Instant::from_std);
+                    tokio::select! {
+                        _ = &mut rx => break,
+                        _ = tokio::time::sleep_until(frame_at.unwrap_or_else(tokio::time::Instant::now)), if frame_at.is_some() => {
+                            if let Err(e) = gui.tick() {
+                                log::error!("Failed to draw background frame: {:?}", e);
+                            }
+                        }
+                        _ = scroll_timer.tick() => {
+                            if let Err(e) = gui.scroll_text(playback.position()) {
+                                log::error!("Error scrolling text: {:?}", e);
+                            }
+                        }
+                    }
+                }
                 gui.display_flush().unwrap();
             }
 
@@ -315,14 +618,41 @@ pub async fn main_work<'d>(
             }
             Event::ServerEvent(ServerEvent::HelloEnd) => {
                 log::info!("Received hello end");
-                if let Err(_) = player_tx.send(AudioData::SetHelloEnd) {
+                let (tx, rx) = tokio::sync::oneshot::channel();
+                if let Err(_) = player_tx.send(AudioData::SetHelloEnd(tx)) {
                     log::error!("Error sending hello end");
                     gui.state = "Error on hello end".to_string();
                     gui.display_flush().unwrap();
+                    continue;
+                }
+                match rx.await {
+                    Ok(Ok(())) => {
+                        gui.state = "Hello set".to_string();
+                        gui.display_flush().unwrap();
+                    }
+                    Ok(Err(e)) => {
+                        log::error!("Invalid hello audio: {:?}", e);
+                        gui.state = "Invalid hello audio".to_string();
+                        gui.text = e.to_string();
+                        gui.display_flush().unwrap();
+                        server
+                            .send(Message::text(format!("Error:Hello:{e}")))
+                            .await?;
+                    }
+                    Err(_) => {
+                        log::error!("Hello end dropped by player");
+                    }
+                }
+            }
+            Event::ServerEvent(ServerEvent::HelloReset) => {
+                log::info!("Received hello reset");
+                if let Err(_) = player_tx.send(AudioData::ResetHello) {
+                    log::error!("Error sending hello reset");
+                    gui.state = "Error on hello reset".to_string();
                 } else {
-                    gui.state = "Hello set".to_string();
-                    gui.display_flush().unwrap();
+                    gui.state = "Hello reset".to_string();
                 }
+                gui.display_flush().unwrap();
             }
             Event::ServerEvent(ServerEvent::BGStart) => {
                 new_gui_bg = vec![];
@@ -334,11 +664,14 @@ pub async fn main_work<'d>(
             Event::ServerEvent(ServerEvent::BGEnd) => {
                 log::info!("Received background end");
                 if !new_gui_bg.is_empty() {
-                    let gui_ = crate::ui::UI::new(Some(&new_gui_bg));
-                    new_gui_bg.clear();
+                    let gif = std::mem::take(&mut new_gui_bg);
+                    let gui_ = crate::ui::UI::new(Some(std::borrow::Cow::Owned(gif)));
                     match gui_ {
                         Ok(new_gui) => {
                             gui = new_gui;
+                            gui.set_panels(setting.lock().unwrap().panels);
+                            gui.set_theme(setting.lock().unwrap().theme);
+                            gui.set_status(status)?;
                             gui.state = "Background data loaded".to_string();
                             gui.display_flush().unwrap();
                         }
@@ -353,6 +686,56 @@ pub async fn main_work<'d>(
                 }
             }
             Event::ServerEvent(ServerEvent::StartVideo | ServerEvent::EndVideo) => {}
+            Event::ServerEvent(ServerEvent::OtaStart {
+                version,
+                size,
+                sha256,
+                signature,
+            }) => {
+                // A new update replaces an unfinished one, which is aborted.
+                ota = None;
+                match crate::ota::Update::begin(&version, size as usize, &sha256, &signature) {
+                    Ok(update) => {
+                        ota = Some(update);
+                        ota_percent = 0;
+                        gui.state = format!("Updating firmware to {version}...");
+                        gui.text.clear();
+                        gui.display_flush().unwrap();
+                    }
+                    Err(e) => ota_failed(&mut server, &mut gui, e).await?,
+                }
+            }
+            Event::ServerEvent(ServerEvent::OtaChunk { data }) => {
+                let Some(update) = &mut ota else {
+                    log::warn!("Received firmware chunk without an update");
+                    continue;
+                };
+                if let Err(e) = update.write(&data) {
+                    ota = None;
+                    ota_failed(&mut server, &mut gui, e).await?;
+                } else if update.percent() >= ota_percent + 5 {
+                    ota_percent = update.percent();
+                    gui.text = format!("{}%", ota_percent);
+                    gui.display_flush().unwrap();
+                }
+            }
+            Event::ServerEvent(ServerEvent::OtaEnd) => {
+                let Some(update) = ota.take() else {
+                    log::warn!("Received firmware end without an update");
+                    continue;
+                };
+                match update.finish() {
+                    Ok(()) => {
+                        gui.state = "Firmware updated, restarting...".to_string();
+                        gui.text.clear();
+                        gui.display_flush().unwrap();
+                        server.send(Message::text("Ota:Done")).await?;
+                        tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+                        unsafe { esp_idf_svc::sys::esp_restart() }
+                    }
+                    Err(e) => ota_failed(&mut server, &mut gui, e).await?,
+                }
+            }
         }
     }
 
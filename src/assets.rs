@@ -0,0 +1,160 @@
+use crate::storage;
+
+/// Directory of the assets on the storage partition.
+const DIR: &str = "assets";
+
+pub const BACKGROUND_GIF: &str = "background.gif";
+pub const MAX_BACKGROUND_GIF_SIZE: usize = 1024 * 1024;
+/// Hello audio set by the server, 16 kHz mono PCM.
+pub const HELLO: &str = "hello.pcm";
+/// 10 seconds of 16 kHz mono PCM.
+pub const MAX_HELLO_SIZE: usize = 10 * 2 * crate::audio::SAMPLE_RATE as usize;
+
+/// The assets that can be stored and their largest size. Together with the
+/// EAP CA certificate they fit the 2 MB storage partition, diagnostic
+/// recordings only use what they leave, see [`headroom`].
+const QUOTAS: &[(&str, usize)] = &[
+    (BACKGROUND_GIF, MAX_BACKGROUND_GIF_SIZE),
+    (HELLO, MAX_HELLO_SIZE),
+];
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize)]
+pub struct Asset {
+    pub name: String,
+    pub size: usize,
+    /// CRC32 of the content, the same checksum as in [`crate::transfer`].
+    pub crc32: u32,
+}
+
+fn check_name(name: &str) -> anyhow::Result<()> {
+    if name.is_empty()
+        || name.len() > 64
+        || name.starts_with('.')
+        || name.ends_with(".tmp")
+        || name.contains(['/', '\\'])
+    {
+        anyhow::bail!("Invalid asset name {:?}", name);
+    }
+    Ok(())
+}
+
+fn file(name: &str) -> String {
+    format!("{}/{}", DIR, name)
+}
+
+/// Creates the asset directory, the storage partition must be mounted.
+pub fn init() -> std::io::Result<()> {
+    std::fs::create_dir_all(storage::path(DIR))
+}
+
+fn quota(name: &str) -> anyhow::Result<usize> {
+    QUOTAS
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, max)| *max)
+        .ok_or_else(|| anyhow::anyhow!("Unknown asset {:?}", name))
+}
+
+/// Fails when [`put`] would refuse `len` bytes under `name`, so that an
+/// upload can be refused before it is received.
+pub fn check_put(name: &str, len: usize) -> anyhow::Result<()> {
+    check_name(name)?;
+    let max = quota(name)?;
+    if len > max {
+        anyhow::bail!("Asset {} is {} bytes, max {}", name, len, max);
+    }
+    let needed = storage::allocated(storage::CHECKED_HEADER_LEN + len);
+    let replaced = info(name).map_or(0, |a| {
+        storage::allocated(storage::CHECKED_HEADER_LEN + a.size)
+    });
+    let free = storage::free_space()? + replaced;
+    if free < needed {
+        anyhow::bail!(
+            "Not enough space for {}: {} bytes needed, {} free",
+            name,
+            needed,
+            free
+        );
+    }
+    Ok(())
+}
+
+/// Stores `data` under `name`, replacing the previous content atomically
+/// when there is room for both.
+pub fn put(name: &str, data: &[u8]) -> anyhow::Result<Asset> {
+    check_put(name, data.len())?;
+    let needed = storage::allocated(storage::CHECKED_HEADER_LEN + data.len());
+    if storage::free_space()? < needed {
+        log::warn!("Not enough space to replace {} atomically", name);
+        storage::remove(&file(name))?;
+    }
+    let crc32 = storage::write_checked(&file(name), data)?;
+    log::info!("Asset {} saved, {} bytes", name, data.len());
+    Ok(Asset {
+        name: name.to_string(),
+        size: data.len(),
+        crc32,
+    })
+}
+
+/// The content of `name`, `None` if it is missing, corrupted or larger than
+/// `max_len`.
+pub fn get(name: &str, max_len: usize) -> Option<Vec<u8>> {
+    check_name(name).ok()?;
+    storage::read_checked(&file(name), max_len)
+}
+
+pub fn info(name: &str) -> Option<Asset> {
+    check_name(name).ok()?;
+    let (size, crc32) = storage::checked_header(&file(name))?;
+    Some(Asset {
+        name: name.to_string(),
+        size,
+        crc32,
+    })
+}
+
+/// Space the assets may still take up to their quotas, which other files
+/// on the storage partition must leave free.
+pub fn headroom() -> u64 {
+    QUOTAS
+        .iter()
+        .map(|&(name, max)| {
+            let used = info(name).map_or(0, |a| {
+                storage::allocated(storage::CHECKED_HEADER_LEN + a.size.min(max))
+            });
+            storage::allocated(storage::CHECKED_HEADER_LEN + max) - used
+        })
+        .sum()
+}
+
+/// All readable assets, by name.
+pub fn list() -> anyhow::Result<Vec<Asset>> {
+    let mut assets = vec![];
+    for entry in std::fs::read_dir(storage::path(DIR))? {
+        let name = entry?.file_name();
+        if let Some(asset) = name.to_str().and_then(info) {
+            assets.push(asset);
+        }
+    }
+    assets.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(assets)
+}
+
+/// Removes `name`, a missing asset is not an error.
+pub fn delete(name: &str) -> anyhow::Result<()> {
+    check_name(name)?;
+    storage::remove(&file(name))?;
+    log::info!("Asset {} deleted", name);
+    Ok(())
+}
+
+#[test]
+fn test_check_name() {
+    assert!(check_name(BACKGROUND_GIF).is_ok());
+    assert!(check_name(HELLO).is_ok());
+    assert!(check_name("").is_err());
+    assert!(check_name("../nvs").is_err());
+    assert!(check_name(".hidden").is_err());
+    assert!(check_name("hello.pcm.tmp").is_err());
+}
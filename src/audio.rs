@@ -1,12 +1,13 @@
 use std::sync::Arc;
 
-use esp_idf_svc::hal::gpio::AnyIOPin;
-use esp_idf_svc::hal::i2s::{config, I2sDriver, I2S0, I2S1};
-
 use esp_idf_svc::sys::esp_sr;
 
-const SAMPLE_RATE: u32 = 16000;
-const PORT_TICK_PERIOD_MS: u32 = 1000 / esp_idf_svc::sys::configTICK_RATE_HZ;
+use crate::board::{Audio, AudioIo};
+use crate::resample::Resampler;
+
+/// Sample rate of the I2S bus, the microphone and the AFE. Speaker audio at
+/// other rates is resampled in software.
+pub const SAMPLE_RATE: u32 = 16000;
 
 unsafe fn afe_init() -> (
     *mut esp_sr::esp_afe_sr_iface_t,
@@ -126,12 +127,62 @@ impl AFE {
 
 pub static WAKE_WAV: &[u8] = include_bytes!("../assets/hello_beep.wav");
 
+/// Largest hello WAV file accepted, 16-bit stereo at
+/// [`crate::resample::MAX_RATE`] that still fits
+/// [`crate::assets::MAX_HELLO_SIZE`] once converted.
+pub const MAX_HELLO_WAV_SIZE: usize = crate::wav::HEADER_LEN
+    + crate::assets::MAX_HELLO_SIZE * 2 * (crate::resample::MAX_RATE / SAMPLE_RATE) as usize;
+
+/// Parses a WAV file into PCM samples that can be written to I2S as is.
+fn hello_pcm(wav: &[u8]) -> anyhow::Result<Vec<u8>> {
+    let pcm = crate::wav::parse(wav)?.to_pcm16_mono(SAMPLE_RATE)?;
+    if pcm.len() > crate::assets::MAX_HELLO_SIZE {
+        anyhow::bail!(
+            "Hello audio too long: {} bytes, max {}",
+            pcm.len(),
+            crate::assets::MAX_HELLO_SIZE
+        );
+    }
+    Ok(pcm.into_owned())
+}
+
+/// Loads the saved hello audio, falling back to [`WAKE_WAV`].
+fn load_hello() -> anyhow::Result<Vec<u8>> {
+    if let Some(pcm) = crate::assets::get(crate::assets::HELLO, crate::assets::MAX_HELLO_SIZE) {
+        log::info!("Loaded saved hello audio, {} bytes", pcm.len());
+        return Ok(pcm);
+    }
+    hello_pcm(WAKE_WAV)
+}
+
+fn save_hello(pcm: &[u8]) {
+    if let Err(e) = crate::assets::put(crate::assets::HELLO, pcm) {
+        log::error!("Failed to save hello audio: {:?}", e);
+    }
+}
+
+/// Validates a WAV file and saves it as the hello audio played from the next boot.
+pub fn store_hello(wav: &[u8]) -> anyhow::Result<()> {
+    let pcm = hello_pcm(wav)?;
+    crate::assets::put(crate::assets::HELLO, &pcm)?;
+    Ok(())
+}
+
+fn reset_hello() -> anyhow::Result<Vec<u8>> {
+    if let Err(e) = crate::assets::delete(crate::assets::HELLO) {
+        log::error!("Failed to remove saved hello audio: {:?}", e);
+    }
+    hello_pcm(WAKE_WAV)
+}
+
 pub enum AudioData {
     Hello(tokio::sync::oneshot::Sender<()>),
     SetHelloStart,
     SetHelloChunk(Vec<u8>),
-    SetHelloEnd,
-    Start,
+    SetHelloEnd(tokio::sync::oneshot::Sender<anyhow::Result<()>>),
+    ResetHello,
+    /// Starts a response with audio at the given sample rate.
+    Start(u32),
     Chunk(Vec<u8>),
     End(tokio::sync::oneshot::Sender<()>),
 }
@@ -139,22 +190,14 @@ pub enum AudioData {
 pub type PlayerTx = tokio::sync::mpsc::UnboundedSender<AudioData>;
 pub type PlayerRx = tokio::sync::mpsc::UnboundedReceiver<AudioData>;
 pub type MicTx = tokio::sync::mpsc::Sender<crate::app::Event>;
+pub type Recorder = Option<Arc<crate::diag::Recorder>>;
 
-pub async fn i2s_task_(
-    i2s: I2S0,
-    ws: AnyIOPin,
-    sck: AnyIOPin,
-    din: AnyIOPin,
-    i2s1: I2S1,
-    bclk: AnyIOPin,
-    lrclk: AnyIOPin,
-    dout: AnyIOPin,
-    (tx, rx): (MicTx, PlayerRx),
-) {
+pub async fn i2s_task(audio: Audio, (tx, rx): (MicTx, PlayerRx), recorder: Recorder) {
     let afe_handle = Arc::new(AFE::new());
     let afe_handle_ = afe_handle.clone();
-    let afe_r = std::thread::spawn(|| afe_worker(afe_handle_, tx));
-    let r = i2s_player_(i2s, ws, sck, din, i2s1, bclk, lrclk, dout, afe_handle, rx).await;
+    let recorder_ = recorder.clone();
+    let afe_r = std::thread::spawn(|| afe_worker(afe_handle_, tx, recorder_));
+    let r = i2s_player(audio, afe_handle, rx, recorder).await;
     if let Err(e) = r {
         log::error!("Error: {}", e);
     } else {
@@ -168,43 +211,21 @@ pub async fn i2s_task_(
     }
 }
 
-async fn i2s_player_(
-    i2s: I2S0,
-    ws: AnyIOPin,
-    sck: AnyIOPin,
-    din: AnyIOPin,
-    i2s1: I2S1,
-    bclk: AnyIOPin,
-    lrclk: AnyIOPin,
-    dout: AnyIOPin,
+async fn i2s_player(
+    mut audio: Audio,
     afe_handle: Arc<AFE>,
     mut rx: PlayerRx,
+    recorder: Recorder,
 ) -> anyhow::Result<()> {
-    let i2s_config = config::StdConfig::new(
-        config::Config::default().auto_clear(true),
-        config::StdClkConfig::from_sample_rate_hz(SAMPLE_RATE),
-        config::StdSlotConfig::philips_slot_default(
-            config::DataBitWidth::Bits16,
-            config::SlotMode::Mono,
-        ),
-        config::StdGpioConfig::default(),
-    );
-
-    let mclk: Option<esp_idf_svc::hal::gpio::AnyIOPin> = None;
-    let mut rx_driver = I2sDriver::new_std_rx(i2s, &i2s_config, sck, din, mclk, ws).unwrap();
-    rx_driver.rx_enable()?;
-
-    let mclk: Option<esp_idf_svc::hal::gpio::AnyIOPin> = None;
-    let mut tx_driver = I2sDriver::new_std_tx(i2s1, &i2s_config, bclk, dout, mclk, lrclk).unwrap();
-    tx_driver.tx_enable()?;
-
     // 10ms
     let mut buf = [0u8; 2 * 160];
     let mut speaking = false;
 
-    let mut hello_audio = WAKE_WAV.to_vec();
+    let mut hello_audio = load_hello()?;
+    let mut new_hello = vec![];
+    let mut resampler = Resampler::new(SAMPLE_RATE, SAMPLE_RATE);
 
-    tx_driver.write_all(&hello_audio, 100 / PORT_TICK_PERIOD_MS)?;
+    audio.write_all(&hello_audio)?;
     log::info!("Playing hello audio, waiting for response...");
 
     loop {
@@ -216,9 +237,12 @@ async fn i2s_player_(
                     Some(data)
                 }
                 _ = async {} => {
-                    for _ in 0..10{
-                        let n = rx_driver.read(&mut buf, 100 / PORT_TICK_PERIOD_MS)?;
+                    for _ in 0..Audio::READS_PER_POLL {
+                        let n = audio.read(&mut buf)?;
                         afe_handle.feed(&buf[..n]);
+                        if let Some(recorder) = &recorder {
+                            recorder.feed_raw(&buf[..n]);
+                        }
                     }
                     None
                 }
@@ -228,166 +252,60 @@ async fn i2s_player_(
             match data {
                 AudioData::Hello(tx) => {
                     log::info!("Received hello");
-                    tx_driver
+                    audio
                         .write_all_async(&hello_audio)
                         .await
                         .map_err(|e| anyhow::anyhow!("Error play hello: {:?}", e))?;
+                    log::info!("Hello audio sent, notifying");
                     let _ = tx.send(());
                     speaking = false;
                 }
                 AudioData::SetHelloStart => {
                     log::info!("Received set hello start");
-                    hello_audio.clear();
+                    new_hello.clear();
                 }
                 AudioData::SetHelloChunk(data) => {
                     log::info!("Received set hello chunk");
-                    hello_audio.extend(data);
+                    new_hello.extend(data);
                 }
-                AudioData::SetHelloEnd => {
+                AudioData::SetHelloEnd(tx) => {
                     log::info!("Received set hello end");
-                    tx_driver
-                        .write_all_async(&hello_audio)
-                        .await
-                        .map_err(|e| anyhow::anyhow!("Error play set hello: {:?}", e))?;
-                }
-                AudioData::Start => {
-                    log::info!("Received start");
-                    speaking = true;
-                }
-                AudioData::Chunk(data) => {
-                    log::info!("Received audio chunk");
-                    if speaking {
-                        tx_driver
-                            .write_all_async(&data)
-                            .await
-                            .map_err(|e| anyhow::anyhow!("Error play audio data: {:?}", e))?;
+                    match hello_pcm(&new_hello) {
+                        Ok(pcm) => {
+                            save_hello(&pcm);
+                            hello_audio = pcm;
+                            new_hello = vec![];
+                            let _ = tx.send(Ok(()));
+                            audio
+                                .write_all_async(&hello_audio)
+                                .await
+                                .map_err(|e| anyhow::anyhow!("Error play set hello: {:?}", e))?;
+                        }
+                        Err(e) => {
+                            log::error!("Invalid hello audio: {:?}", e);
+                            new_hello = vec![];
+                            let _ = tx.send(Err(e));
+                        }
                     }
                 }
-                AudioData::End(tx) => {
-                    log::info!("Received end");
-                    let _ = tx.send(());
-                    speaking = false;
-                    tokio::time::sleep(std::time::Duration::from_millis(300)).await;
+                AudioData::ResetHello => {
+                    log::info!("Received reset hello");
+                    hello_audio = reset_hello()?;
                 }
-            }
-        } else {
-            tokio::time::sleep(std::time::Duration::from_millis(100)).await;
-        }
-    }
-
-    // Ok(())
-}
-
-pub async fn i2s_task(
-    i2s: I2S0,
-    bclk: AnyIOPin,
-    din: AnyIOPin,
-    dout: AnyIOPin,
-    ws: AnyIOPin,
-    (tx, rx): (MicTx, PlayerRx),
-) {
-    let afe_handle = Arc::new(AFE::new());
-    let afe_handle_ = afe_handle.clone();
-    let afe_r = std::thread::spawn(|| afe_worker(afe_handle_, tx));
-    let r = i2s_player(i2s, bclk, din, dout, ws, afe_handle, rx).await;
-    if let Err(e) = r {
-        log::error!("Error: {}", e);
-    } else {
-        log::info!("I2S test completed successfully");
-    }
-    let r = afe_r.join().unwrap();
-    if let Err(e) = r {
-        log::error!("Error: {}", e);
-    } else {
-        log::info!("AFE worker completed successfully");
-    }
-}
-
-async fn i2s_player(
-    i2s: I2S0,
-    bclk: AnyIOPin,
-    din: AnyIOPin,
-    dout: AnyIOPin,
-    ws: AnyIOPin,
-    afe_handle: Arc<AFE>,
-    mut rx: PlayerRx,
-) -> anyhow::Result<()> {
-    log::info!("PORT_TICK_PERIOD_MS = {}", PORT_TICK_PERIOD_MS);
-    let i2s_config = config::StdConfig::new(
-        config::Config::default().auto_clear(true),
-        config::StdClkConfig::from_sample_rate_hz(SAMPLE_RATE),
-        config::StdSlotConfig::philips_slot_default(
-            config::DataBitWidth::Bits16,
-            config::SlotMode::Mono,
-        ),
-        config::StdGpioConfig::default(),
-    );
-
-    let mclk: Option<esp_idf_svc::hal::gpio::AnyIOPin> = None;
-
-    let mut driver = I2sDriver::new_std_bidir(i2s, &i2s_config, bclk, din, dout, mclk, ws).unwrap();
-    driver.tx_enable()?;
-    driver.rx_enable()?;
-
-    let mut buf = [0u8; 2 * 160];
-    let mut speaking = false;
-
-    let mut hello_audio = WAKE_WAV.to_vec();
-
-    driver.write_all(&hello_audio, 100 / PORT_TICK_PERIOD_MS)?;
-    log::info!("Playing hello audio, waiting for response...");
-
-    loop {
-        let data = if speaking {
-            rx.recv().await
-        } else {
-            tokio::select! {
-                Some(data) = rx.recv() =>{
-                    Some(data)
-                }
-                _ = async {} => {
-                    let n = driver.read(&mut buf, 100 / PORT_TICK_PERIOD_MS)?;
-                    afe_handle.feed(&buf[..n]);
-                    None
-                }
-            }
-        };
-        if let Some(data) = data {
-            match data {
-                AudioData::Hello(tx) => {
-                    log::info!("Received hello");
-                    driver
-                        .write_all_async(&hello_audio)
-                        .await
-                        .map_err(|e| anyhow::anyhow!("Error play hello: {:?}", e))?;
-                    log::info!("Hello audio sent, notifying");
-                    let _ = tx.send(());
-                    log::info!("Hello audio sent, notifying done");
-                    speaking = false;
-                }
-                AudioData::SetHelloStart => {
-                    log::info!("Received set hello start");
-                    hello_audio.clear();
-                }
-                AudioData::SetHelloChunk(data) => {
-                    log::info!("Received set hello chunk");
-                    hello_audio.extend(data);
-                }
-                AudioData::SetHelloEnd => {
-                    log::info!("Received set hello end");
-                    driver
-                        .write_all_async(&hello_audio)
-                        .await
-                        .map_err(|e| anyhow::anyhow!("Error play set hello: {:?}", e))?;
-                }
-                AudioData::Start => {
-                    log::info!("Received start");
+                AudioData::Start(sample_rate) => {
+                    log::info!("Received start, sample rate {}", sample_rate);
+                    resampler = Resampler::new(sample_rate, SAMPLE_RATE);
                     speaking = true;
                 }
                 AudioData::Chunk(data) => {
                     log::info!("Received audio chunk");
                     if speaking {
-                        driver
+                        let data = if resampler.is_passthrough() {
+                            data
+                        } else {
+                            resampler.process(&data)
+                        };
+                        audio
                             .write_all_async(&data)
                             .await
                             .map_err(|e| anyhow::anyhow!("Error play audio data: {:?}", e))?;
@@ -400,6 +318,8 @@ async fn i2s_player(
                     tokio::time::sleep(std::time::Duration::from_millis(300)).await;
                 }
             }
+        } else if let Some(idle) = Audio::IDLE_SLEEP {
+            tokio::time::sleep(idle).await;
         } else {
             tokio::task::yield_now().await;
         }
@@ -408,7 +328,7 @@ async fn i2s_player(
     // Ok(())
 }
 
-fn afe_worker(afe_handle: Arc<AFE>, tx: MicTx) -> anyhow::Result<()> {
+fn afe_worker(afe_handle: Arc<AFE>, tx: MicTx, recorder: Recorder) -> anyhow::Result<()> {
     let mut speech = false;
     loop {
         let result = afe_handle.fetch();
@@ -423,6 +343,9 @@ fn afe_worker(afe_handle: Arc<AFE>, tx: MicTx) -> anyhow::Result<()> {
         if result.speech {
             speech = true;
             log::debug!("Speech detected, sending {} bytes", result.data.len());
+            if let Some(recorder) = &recorder {
+                recorder.feed_afe(&result.data);
+            }
             tx.blocking_send(crate::app::Event::MicAudioChunk(result.data))
                 .map_err(|_| anyhow::anyhow!("Failed to send data"))?;
             continue;
@@ -433,6 +356,9 @@ fn afe_worker(afe_handle: Arc<AFE>, tx: MicTx) -> anyhow::Result<()> {
             tx.blocking_send(crate::app::Event::MicAudioEnd)
                 .map_err(|_| anyhow::anyhow!("Failed to send data"))?;
             speech = false;
+            if let Some(recorder) = &recorder {
+                recorder.finish_utterance();
+            }
         }
     }
 }
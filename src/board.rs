@@ -0,0 +1,442 @@
+use esp_idf_svc::hal::gpio::{AnyIOPin, Input, InterruptType, PinDriver, Pull};
+use esp_idf_svc::hal::i2s::{config, I2sDriver, I2S0, I2S1};
+use esp_idf_svc::sys::{esp_lcd_panel_handle_t, EspError};
+
+const PORT_TICK_PERIOD_MS: u32 = 1000 / esp_idf_svc::sys::configTICK_RATE_HZ;
+
+/// Pins, display and codec of a board. Built-in descriptions live in
+/// `boards/`, a custom one can be selected at build time with
+/// `ECHOKIT_BOARD_CONFIG=path/to/board.rs`.
+#[derive(Debug, Clone, Copy)]
+pub struct BoardConfig {
+    pub name: &'static str,
+    /// K0, active low.
+    pub button: i32,
+    pub codec: Codec,
+    pub audio: AudioPins,
+    pub display: DisplayConfig,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum Codec {
+    /// I2S microphone and amplifier that need no configuration.
+    None,
+    /// ES8311 behind the XL9555 expander, both on the `hal_driver` I2C bus.
+    Es8311 { volume: u8 },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum AudioPins {
+    /// Microphone on I2S0 and speaker on I2S1.
+    Split {
+        mic_ws: i32,
+        mic_sck: i32,
+        mic_din: i32,
+        spk_bclk: i32,
+        spk_lrclk: i32,
+        spk_dout: i32,
+    },
+    /// Microphone and speaker sharing the clocks of I2S0.
+    Bidir {
+        bclk: i32,
+        ws: i32,
+        din: i32,
+        dout: i32,
+    },
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DisplayConfig {
+    pub width: usize,
+    pub height: usize,
+    pub controller: DisplayController,
+}
+
+#[derive(Debug, Clone, Copy)]
+pub enum DisplayController {
+    St7789 {
+        mosi: i32,
+        clk: i32,
+        cs: i32,
+        dc: i32,
+        rst: i32,
+        pclk_hz: u32,
+        mirror_x: bool,
+        mirror_y: bool,
+        swap_xy: bool,
+        invert_color: bool,
+    },
+    /// Display set up by `lcd_init` of the `hal_driver` component.
+    HalDriver,
+}
+
+#[cfg(echokit_board_config)]
+pub const CONFIG: BoardConfig = include!(env!("ECHOKIT_BOARD_CONFIG_FILE"));
+#[cfg(all(not(echokit_board_config), feature = "boards"))]
+pub const CONFIG: BoardConfig = include!("../boards/echokit-boards.rs");
+#[cfg(all(not(echokit_board_config), feature = "box"))]
+pub const CONFIG: BoardConfig = include!("../boards/echokit-box.rs");
+
+pub type Button = PinDriver<'static, AnyIOPin, Input>;
+
+/// Microphone input and speaker output of a board.
+pub trait AudioIo {
+    /// Number of 10ms microphone reads between two checks of the player queue.
+    const READS_PER_POLL: usize;
+    /// Pause after a poll that had nothing to play, `None` only yields.
+    const IDLE_SLEEP: Option<std::time::Duration>;
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError>;
+    fn write_all(&mut self, data: &[u8]) -> Result<(), EspError>;
+    async fn write_all_async(&mut self, data: &[u8]) -> Result<(), EspError>;
+}
+
+pub struct Parts<A> {
+    pub audio: A,
+    /// K0, active low.
+    pub button: Button,
+}
+
+/// A hardware variant, selected as [`CurrentBoard`] behind a cargo feature.
+/// Its pins come from [`CONFIG`].
+pub trait Board {
+    type Audio: AudioIo;
+
+    /// Initializes the codec, the display, the I2S bus and the buttons.
+    ///
+    /// The pins named in [`CONFIG`] are taken by number, nothing else in the
+    /// firmware may use them.
+    fn init(i2s0: I2S0, i2s1: I2S1) -> anyhow::Result<Parts<Self::Audio>>;
+
+    /// Battery charge in percent, `None` without a fuel gauge. No supported
+    /// board has one yet.
+    fn battery() -> Option<u8> {
+        None
+    }
+}
+
+#[cfg(feature = "boards")]
+pub type CurrentBoard = DevBoard;
+#[cfg(feature = "box")]
+pub type CurrentBoard = BoxBoard;
+
+// A custom `ECHOKIT_BOARD_CONFIG` must match the I2S layout of the feature.
+#[cfg(feature = "boards")]
+const _: () = assert!(
+    matches!(CONFIG.audio, AudioPins::Split { .. }),
+    "the `boards` feature needs `AudioPins::Split`"
+);
+#[cfg(feature = "box")]
+const _: () = assert!(
+    matches!(CONFIG.audio, AudioPins::Bidir { .. }),
+    "the `box` feature needs `AudioPins::Bidir`"
+);
+
+pub type Audio = <CurrentBoard as Board>::Audio;
+
+fn i2s_config() -> config::StdConfig {
+    config::StdConfig::new(
+        config::Config::default().auto_clear(true),
+        config::StdClkConfig::from_sample_rate_hz(crate::audio::SAMPLE_RATE),
+        config::StdSlotConfig::philips_slot_default(
+            config::DataBitWidth::Bits16,
+            config::SlotMode::Mono,
+        ),
+        config::StdGpioConfig::default(),
+    )
+}
+
+/// # Safety
+/// The pin must not be driven by anything else.
+unsafe fn pin(num: i32) -> AnyIOPin {
+    AnyIOPin::new(num)
+}
+
+fn button(num: i32) -> anyhow::Result<Button> {
+    let mut button = PinDriver::input(unsafe { pin(num) })?;
+    button.set_pull(Pull::Up)?;
+    button.set_interrupt_type(InterruptType::PosEdge)?;
+    Ok(button)
+}
+
+pub fn init_codec() {
+    match CONFIG.codec {
+        Codec::None => {}
+        Codec::Es8311 { volume } => {
+            use crate::audio::SAMPLE_RATE;
+            use esp_idf_svc::sys::hal_driver;
+
+            unsafe {
+                hal_driver::myiic_init();
+                hal_driver::xl9555_init();
+                hal_driver::es8311_init(SAMPLE_RATE as i32);
+                hal_driver::xl9555_pin_write(hal_driver::SPK_CTRL_IO as _, 1);
+                hal_driver::es8311_set_voice_volume(volume as _); /* 设置喇叭音量，建议不超过65 */
+                hal_driver::es8311_set_voice_mute(0); /* 打开DAC */
+            }
+        }
+    }
+}
+
+/// Speaker volume in percent, `None` if the board cannot tell.
+pub fn volume() -> Option<u8> {
+    match CONFIG.codec {
+        Codec::None => None,
+        Codec::Es8311 { .. } => {
+            let mut volume = 0;
+            let e = unsafe { esp_idf_svc::sys::hal_driver::es8311_get_voice_volume(&mut volume) };
+            (e == 0).then_some(volume.clamp(0, 100) as u8)
+        }
+    }
+}
+
+/// Battery charge in percent, see [`Board::battery`].
+pub fn battery() -> Option<u8> {
+    CurrentBoard::battery()
+}
+
+static mut ESP_LCD_PANEL_HANDLE: esp_lcd_panel_handle_t = std::ptr::null_mut();
+
+pub fn init_display() -> Result<(), EspError> {
+    match CONFIG.display.controller {
+        DisplayController::St7789 {
+            mosi,
+            clk,
+            cs,
+            dc,
+            rst,
+            pclk_hz,
+            mirror_x,
+            mirror_y,
+            swap_xy,
+            invert_color,
+        } => {
+            use esp_idf_svc::sys::*;
+            const GPIO_NUM_NC: i32 = -1;
+
+            let mut buscfg = spi_bus_config_t::default();
+            buscfg.__bindgen_anon_1.mosi_io_num = mosi;
+            buscfg.__bindgen_anon_2.miso_io_num = GPIO_NUM_NC;
+            buscfg.sclk_io_num = clk;
+            buscfg.__bindgen_anon_3.quadwp_io_num = GPIO_NUM_NC;
+            buscfg.__bindgen_anon_4.quadhd_io_num = GPIO_NUM_NC;
+            buscfg.max_transfer_sz =
+                (CONFIG.display.width * CONFIG.display.height * std::mem::size_of::<u16>()) as i32;
+            esp!(unsafe {
+                spi_bus_initialize(
+                    spi_host_device_t_SPI3_HOST,
+                    &buscfg,
+                    spi_common_dma_t_SPI_DMA_CH_AUTO,
+                )
+            })?;
+
+            ::log::info!("Install panel IO");
+            let mut panel_io: esp_lcd_panel_io_handle_t = std::ptr::null_mut();
+            let mut io_config = esp_lcd_panel_io_spi_config_t::default();
+            io_config.cs_gpio_num = cs;
+            io_config.dc_gpio_num = dc;
+            io_config.spi_mode = 3;
+            io_config.pclk_hz = pclk_hz;
+            io_config.trans_queue_depth = 10;
+            io_config.lcd_cmd_bits = 8;
+            io_config.lcd_param_bits = 8;
+            esp!(unsafe {
+                esp_lcd_new_panel_io_spi(
+                    spi_host_device_t_SPI3_HOST as _,
+                    &io_config,
+                    &mut panel_io,
+                )
+            })?;
+
+            ::log::info!("Install LCD driver");
+            let mut panel_config = esp_lcd_panel_dev_config_t::default();
+            let mut panel: esp_lcd_panel_handle_t = std::ptr::null_mut();
+
+            panel_config.reset_gpio_num = rst;
+            panel_config.data_endian = lcd_rgb_data_endian_t_LCD_RGB_DATA_ENDIAN_LITTLE;
+            panel_config.__bindgen_anon_1.rgb_ele_order =
+                lcd_rgb_element_order_t_LCD_RGB_ELEMENT_ORDER_RGB;
+            panel_config.bits_per_pixel = 16;
+
+            esp!(unsafe { esp_lcd_new_panel_st7789(panel_io, &panel_config, &mut panel) })?;
+            unsafe { ESP_LCD_PANEL_HANDLE = panel };
+
+            ::log::info!("Reset LCD panel");
+            unsafe {
+                esp!(esp_lcd_panel_reset(panel))?;
+                esp!(esp_lcd_panel_init(panel))?;
+                esp!(esp_lcd_panel_invert_color(panel, invert_color))?;
+                esp!(esp_lcd_panel_swap_xy(panel, swap_xy))?;
+                esp!(esp_lcd_panel_mirror(panel, mirror_x, mirror_y))?;
+                esp!(esp_lcd_panel_disp_on_off(panel, true))?; /* 启动屏幕 */
+            }
+        }
+        DisplayController::HalDriver => {
+            use esp_idf_svc::sys::hal_driver;
+            unsafe {
+                let config: hal_driver::lcd_cfg_t = std::mem::zeroed();
+                hal_driver::lcd_init(config);
+            }
+        }
+    }
+    ::log::info!("Display {} initialized", CONFIG.name);
+    Ok(())
+}
+
+/// Panel handle of the display set up by [`init_display`].
+pub fn panel_handle() -> esp_lcd_panel_handle_t {
+    match CONFIG.display.controller {
+        DisplayController::St7789 { .. } => unsafe { ESP_LCD_PANEL_HANDLE },
+        DisplayController::HalDriver => unsafe {
+            std::mem::transmute(esp_idf_svc::sys::hal_driver::panel_handle)
+        },
+    }
+}
+
+/// Board with separate I2S peripherals for the microphone and the amplifier.
+#[cfg(feature = "boards")]
+pub struct DevBoard;
+
+#[cfg(feature = "boards")]
+pub struct SplitAudio {
+    rx: I2sDriver<'static, esp_idf_svc::hal::i2s::I2sRx>,
+    tx: I2sDriver<'static, esp_idf_svc::hal::i2s::I2sTx>,
+}
+
+#[cfg(feature = "boards")]
+impl AudioIo for SplitAudio {
+    const READS_PER_POLL: usize = 10;
+    const IDLE_SLEEP: Option<std::time::Duration> = Some(std::time::Duration::from_millis(100));
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+        self.rx.read(buf, 100 / PORT_TICK_PERIOD_MS)
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), EspError> {
+        self.tx.write_all(data, 100 / PORT_TICK_PERIOD_MS)
+    }
+
+    async fn write_all_async(&mut self, data: &[u8]) -> Result<(), EspError> {
+        self.tx.write_all_async(data).await
+    }
+}
+
+#[cfg(feature = "boards")]
+impl Board for DevBoard {
+    type Audio = SplitAudio;
+
+    fn init(i2s0: I2S0, i2s1: I2S1) -> anyhow::Result<Parts<SplitAudio>> {
+        let AudioPins::Split {
+            mic_ws,
+            mic_sck,
+            mic_din,
+            spk_bclk,
+            spk_lrclk,
+            spk_dout,
+        } = CONFIG.audio
+        else {
+            unreachable!("checked at compile time");
+        };
+
+        init_codec();
+        init_display()?;
+
+        let i2s_config = i2s_config();
+
+        let mclk: Option<AnyIOPin> = None;
+        let mut rx = unsafe {
+            I2sDriver::new_std_rx(
+                i2s0,
+                &i2s_config,
+                pin(mic_sck),
+                pin(mic_din),
+                mclk,
+                pin(mic_ws),
+            )?
+        };
+        rx.rx_enable()?;
+
+        let mclk: Option<AnyIOPin> = None;
+        let mut tx = unsafe {
+            I2sDriver::new_std_tx(
+                i2s1,
+                &i2s_config,
+                pin(spk_bclk),
+                pin(spk_dout),
+                mclk,
+                pin(spk_lrclk),
+            )?
+        };
+        tx.tx_enable()?;
+
+        Ok(Parts {
+            audio: SplitAudio { rx, tx },
+            button: button(CONFIG.button)?,
+        })
+    }
+}
+
+/// Board with the microphone and the speaker on one bidirectional I2S bus.
+#[cfg(feature = "box")]
+pub struct BoxBoard;
+
+#[cfg(feature = "box")]
+pub struct BidirAudio(I2sDriver<'static, esp_idf_svc::hal::i2s::I2sBiDir>);
+
+#[cfg(feature = "box")]
+impl AudioIo for BidirAudio {
+    const READS_PER_POLL: usize = 1;
+    const IDLE_SLEEP: Option<std::time::Duration> = None;
+
+    fn read(&mut self, buf: &mut [u8]) -> Result<usize, EspError> {
+        self.0.read(buf, 100 / PORT_TICK_PERIOD_MS)
+    }
+
+    fn write_all(&mut self, data: &[u8]) -> Result<(), EspError> {
+        self.0.write_all(data, 100 / PORT_TICK_PERIOD_MS)
+    }
+
+    async fn write_all_async(&mut self, data: &[u8]) -> Result<(), EspError> {
+        self.0.write_all_async(data).await
+    }
+}
+
+#[cfg(feature = "box")]
+impl Board for BoxBoard {
+    type Audio = BidirAudio;
+
+    fn init(i2s0: I2S0, _i2s1: I2S1) -> anyhow::Result<Parts<BidirAudio>> {
+        let AudioPins::Bidir {
+            bclk,
+            ws,
+            din,
+            dout,
+        } = CONFIG.audio
+        else {
+            unreachable!("checked at compile time");
+        };
+
+        init_codec();
+        init_display()?;
+
+        let mclk: Option<AnyIOPin> = None;
+        let mut driver = unsafe {
+            I2sDriver::new_std_bidir(
+                i2s0,
+                &i2s_config(),
+                pin(bclk),
+                pin(din),
+                pin(dout),
+                mclk,
+                pin(ws),
+            )?
+        };
+        driver.tx_enable()?;
+        driver.rx_enable()?;
+
+        Ok(Parts {
+            audio: BidirAudio(driver),
+            button: button(CONFIG.button)?,
+        })
+    }
+}
@@ -1,15 +1,327 @@
-use std::sync::{Arc, Mutex};
+use std::sync::Mutex;
 
-use esp32_nimble::{utilities::BleUuid, uuid128, BLEAdvertisementData, NimbleProperties};
+use esp32_nimble::{
+    utilities::{mutex::Mutex as NimbleMutex, BleUuid},
+    uuid128, BLEAdvertisementData, BLECharacteristic, BLEServer, NimbleProperties,
+};
+use esp_idf_svc::{eventloop::EspSystemEventLoop, hal::modem::Modem};
+
+use crate::network::SharedModem;
+use crate::transfer::Kind;
+use crate::SharedSetting;
 
 const SERVICE_ID: BleUuid = uuid128!("623fa3e2-631b-4f8f-a6e7-a7b09c03e7e0");
 const SSID_ID: BleUuid = uuid128!("1fda4d6e-2f14-42b0-96fa-453bed238375");
 const PASS_ID: BleUuid = uuid128!("a987ab18-a940-421a-a1d7-b94ee22bccbe");
 const SERVER_URL_ID: BleUuid = uuid128!("cef520a9-bcb5-4fc6-87f7-82804eee2b20");
 const BACKGROUND_GIF_ID: BleUuid = uuid128!("d1f3b2c4-5e6f-4a7b-8c9d-0e1f2a3b4c5d");
+const DIAG_ID: BleUuid = uuid128!("828abd66-826f-463a-bab3-ed2360df84be");
+const TRANSFER_ID: BleUuid = uuid128!("743d01f0-add9-497e-b913-0828ff381828");
+/// JSON status, notified whenever it changes.
+const STATUS_ID: BleUuid = uuid128!("39a4b0bc-bc7a-4e13-898d-0fbc0f87348e");
+/// Wi-Fi scan results as JSON, notified after every scan.
+const SCAN_ID: BleUuid = uuid128!("f14e1568-872c-4ce8-863d-96bddd0f9686");
+/// `personal`, `wpa3` or `enterprise`.
+const AUTH_ID: BleUuid = uuid128!("08d4c8db-d415-457a-97f3-893aa60cdfc3");
+const IDENTITY_ID: BleUuid = uuid128!("69e1f0d0-3998-4aeb-b9a8-6e8af74b8329");
+const USERNAME_ID: BleUuid = uuid128!("eac7a8cf-4a0a-442d-8202-17361d826413");
+/// Saved networks. Reads give `[{"ssid", "priority", "auth", "ip"}]`, `ip`
+/// being the static address or `null` for DHCP. Writing a
+/// [`crate::network::KnownNetwork`] as JSON adds or updates a network and
+/// `{"ssid", "delete": true}` removes it.
+const NETWORKS_ID: BleUuid = uuid128!("dead7d63-c15f-4e5f-ae0b-ea58e5ccaa95");
+/// Static IP configuration of the network set by [`SSID_ID`] as a
+/// [`crate::network::IpConfig`] in JSON, empty for DHCP. Writing an empty
+/// value switches back to DHCP. Saved networks carry their own `ip_config`.
+const IP_CONFIG_ID: BleUuid = uuid128!("05853704-633b-4da0-b276-8887b424040a");
+/// Settings export, see [`crate::settings::Store::export`]. The value is
+/// larger than a characteristic can hold, so writing a `u32` LE offset
+/// selects the page returned by the next reads. Offset 0 takes a fresh
+/// export, a page shorter than [`EXPORT_PAGE_SIZE`] is the last one. The
+/// export is imported as a [`crate::transfer`] of kind settings.
+const EXPORT_ID: BleUuid = uuid128!("a152161c-2273-42cc-90af-91a4c5647a81");
+const EXPORT_PAGE_SIZE: usize = 500;
+/// Overlay panels as a [`crate::ui::Panels`] in JSON.
+const PANELS_ID: BleUuid = uuid128!("4d19b788-95ef-4b45-93a8-4cbd784bc51f");
+/// Reads the [`crate::ui::Theme`] in JSON, takes a
+/// [`crate::ui::ThemeChoice`].
+const THEME_ID: BleUuid = uuid128!("fd830bab-a63f-4b60-abc7-b90a3d20f1ac");
+/// Commands: `test_wifi`, `scan`, `factory_reset`.
+const COMMAND_ID: BleUuid = uuid128!("e52f8a92-4ba9-4483-8ad8-3386f37a83a1");
+
+/// Firmware updates, kept apart from provisioning.
+const DFU_SERVICE_ID: BleUuid = uuid128!("dfdd14e3-4aca-417a-b58a-c0ac17a6f6f3");
+/// Reads give `{"version", "enabled"}` of the running firmware, writing a
+/// [`crate::ota::Manifest`] as JSON prepares the next upload.
+const DFU_INFO_ID: BleUuid = uuid128!("43401cbf-adf3-4a61-9fd8-2a346d333c40");
+/// A [`crate::transfer`] of kind firmware, the image described by the
+/// manifest. The device restarts into it after `DONE`.
+const DFU_TRANSFER_ID: BleUuid = uuid128!("8475e044-c973-4ef6-a4a2-082136bc843d");
+
+/// Keeps the payload of a [`crate::transfer`] in memory until it completes.
+struct TransferSink {
+    setting: SharedSetting,
+    buf: Vec<u8>,
+}
+
+impl crate::transfer::Sink for TransferSink {
+    fn begin(&mut self, kind: Kind, total_size: u32) -> anyhow::Result<()> {
+        let max_size = match kind {
+            Kind::Gif => crate::assets::MAX_BACKGROUND_GIF_SIZE,
+            Kind::Hello => crate::audio::MAX_HELLO_WAV_SIZE,
+            Kind::Cert => crate::network::MAX_EAP_CA_SIZE,
+            Kind::Settings => 4096,
+            Kind::Firmware => anyhow::bail!("Firmware goes to the DFU service"),
+        };
+        if total_size as usize > max_size {
+            anyhow::bail!(
+                "{:?} too large: {} bytes, max {}",
+                kind,
+                total_size,
+                max_size
+            );
+        }
+        self.buf = Vec::with_capacity(total_size as usize);
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        self.buf.extend_from_slice(data);
+        Ok(())
+    }
+
+    fn finish(&mut self, kind: Kind) -> anyhow::Result<()> {
+        let data = std::mem::take(&mut self.buf);
+        match kind {
+            Kind::Gif => {
+                let mut setting = self.setting.lock().unwrap();
+                setting.background_gif = (data, true);
+            }
+            Kind::Hello => crate::audio::store_hello(&data)?,
+            Kind::Cert => {
+                if !data.starts_with(b"-----BEGIN CERTIFICATE-----") {
+                    anyhow::bail!("Not a PEM certificate");
+                }
+                crate::storage::write_checked(crate::network::EAP_CA_FILE, &data)?;
+            }
+            Kind::Settings => self.setting.lock().unwrap().import(&data)?,
+            Kind::Firmware => unreachable!(),
+        }
+        Ok(())
+    }
+
+    fn abort(&mut self, _kind: Kind) {
+        self.buf = Vec::new();
+    }
+}
+
+/// Writes a firmware image straight into the inactive app slot.
+struct DfuSink {
+    manifest: std::sync::Arc<Mutex<Option<crate::ota::Manifest>>>,
+    update: Option<crate::ota::Update>,
+}
+
+impl crate::transfer::Sink for DfuSink {
+    fn begin(&mut self, kind: Kind, total_size: u32) -> anyhow::Result<()> {
+        if kind != Kind::Firmware {
+            anyhow::bail!("{:?} goes to the provisioning service", kind);
+        }
+        let manifest = self.manifest.lock().unwrap().clone();
+        let manifest = manifest.ok_or_else(|| anyhow::anyhow!("No firmware manifest"))?;
+        // Drop a previous update first, there is only one slot.
+        self.update = None;
+        self.update = Some(manifest.begin(total_size as usize)?);
+        Ok(())
+    }
+
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let update = self
+            .update
+            .as_mut()
+            .ok_or_else(|| anyhow::anyhow!("No firmware update"))?;
+        update.write(data)
+    }
+
+    fn finish(&mut self, _kind: Kind) -> anyhow::Result<()> {
+        let update = self
+            .update
+            .take()
+            .ok_or_else(|| anyhow::anyhow!("No firmware update"))?;
+        update.finish()?;
+        self.manifest.lock().unwrap().take();
+        restart_soon();
+        Ok(())
+    }
+
+    fn abort(&mut self, _kind: Kind) {
+        self.update = None;
+    }
+}
+
+/// Adds the firmware update service.
+fn dfu_service(server: &mut BLEServer) {
+    let service = server.create_service(DFU_SERVICE_ID);
+
+    let manifest = std::sync::Arc::new(Mutex::new(None));
+    let manifest_ = manifest.clone();
+    let info_characteristic = service.lock().create_characteristic(
+        DFU_INFO_ID,
+        NimbleProperties::READ | NimbleProperties::WRITE,
+    );
+    info_characteristic
+        .lock()
+        .on_read(|c, _| {
+            let info = serde_json::json!({
+                "version": crate::ota::VERSION,
+                "enabled": crate::ota::enabled(),
+            });
+            c.set_value(info.to_string().as_bytes());
+        })
+        .on_write(move |args| {
+            match serde_json::from_slice::<crate::ota::Manifest>(args.recv_data()) {
+                Ok(m) => {
+                    log::info!("Firmware manifest for {}", m.version);
+                    *manifest_.lock().unwrap() = Some(m);
+                }
+                Err(e) => {
+                    log::error!("Invalid firmware manifest: {:?}", e);
+                    args.reject();
+                }
+            }
+        });
+
+    let mut transfer = crate::transfer::Receiver::new();
+    let mut sink = DfuSink {
+        manifest,
+        update: None,
+    };
+    let (frame_tx, frame_rx) = std::sync::mpsc::channel::<Vec<u8>>();
+    let transfer_characteristic = service.lock().create_characteristic(
+        DFU_TRANSFER_ID,
+        NimbleProperties::WRITE | NimbleProperties::NOTIFY,
+    );
+    transfer_characteristic.lock().on_write(move |args| {
+        let _ = frame_tx.send(args.recv_data().to_vec());
+    });
+    // Flash writes erase a sector every 4 KB and the last chunk checks the
+    // whole image, which would stall the BLE host task. The client waits for
+    // each ack, so frames are handled in order here.
+    let r = std::thread::Builder::new()
+        .stack_size(8 * 1024)
+        .spawn(move || {
+            for frame in frame_rx {
+                let ack = transfer.handle(&frame, &mut sink);
+                transfer_characteristic
+                    .lock()
+                    .set_value(&ack.to_bytes())
+                    .notify();
+            }
+        });
+    if let Err(e) = r {
+        log::error!("Failed to start firmware update task: {:?}", e);
+    }
+}
+
+/// Restarts after the pending notifications had time to go out.
+fn restart_soon() {
+    std::thread::spawn(|| {
+        std::thread::sleep(std::time::Duration::from_secs(2));
+        log::info!("Restarting");
+        unsafe { esp_idf_svc::sys::esp_restart() }
+    });
+}
+
+fn set_status(characteristic: &NimbleMutex<BLECharacteristic>, status: serde_json::Value) {
+    log::info!("Status: {}", status);
+    characteristic
+        .lock()
+        .set_value(status.to_string().as_bytes())
+        .notify();
+}
+
+/// Connects to the saved networks like at boot and reports the result on the
+/// status characteristic, or `busy` while the modem is in use. Stops the
+/// captive portal first. The connection is dropped again afterwards.
+fn test_wifi(
+    setting: &SharedSetting,
+    modem: &Mutex<Modem>,
+    sysloop: EspSystemEventLoop,
+    status: &NimbleMutex<BLECharacteristic>,
+) {
+    crate::portal::stop();
+    let Ok(mut modem) = modem.try_lock() else {
+        log::warn!("Wi-Fi busy, test skipped");
+        set_status(status, serde_json::json!({ "state": "busy" }));
+        return;
+    };
+    let networks = setting.lock().unwrap().known_networks();
+    let ssids = networks
+        .iter()
+        .map(|n| n.ssid.as_str())
+        .collect::<Vec<_>>()
+        .join(", ");
+
+    set_status(
+        status,
+        serde_json::json!({ "state": "testing", "ssid": ssids }),
+    );
+    // Same ranking and fallback as at boot, report the network that won.
+    let r = crate::network::wifi(&networks, &mut *modem, sysloop).and_then(|wifi| {
+        let ssid = wifi
+            .get_configuration()?
+            .as_client_conf_ref()
+            .map(|c| c.ssid.to_string())
+            .unwrap_or_default();
+        Ok((ssid, wifi.sta_netif().get_ip_info()?.ip))
+    });
+    match r {
+        Ok((ssid, ip)) => set_status(
+            status,
+            serde_json::json!({ "state": "connected", "ssid": ssid, "ip": ip.to_string() }),
+        ),
+        Err(e) => set_status(
+            status,
+            serde_json::json!({ "state": "failed", "ssid": ssids, "error": e.to_string() }),
+        ),
+    }
+}
+
+/// Scans for networks and publishes them on the scan characteristic. Stops
+/// the captive portal first.
+fn scan_wifi(
+    modem: &Mutex<Modem>,
+    sysloop: EspSystemEventLoop,
+    scan: &NimbleMutex<BLECharacteristic>,
+) {
+    crate::portal::stop();
+    let Ok(mut modem) = modem.try_lock() else {
+        log::warn!("Wi-Fi busy, scan skipped");
+        scan.lock().notify();
+        return;
+    };
+    match crate::wifi_scan::scan(&mut *modem, sysloop) {
+        Ok(networks) => {
+            // A single read without long-read support is limited to the MTU.
+            let json = crate::wifi_scan::to_json(&networks, 500);
+            scan.lock().set_value(json.as_bytes()).notify();
+        }
+        Err(e) => log::error!("WiFi scan failed: {:?}", e),
+    }
+}
+
+/// Runs a Wi-Fi task in the background, they take seconds and must not
+/// block the BLE host task.
+fn spawn_wifi_task(f: impl FnOnce() + Send + 'static) {
+    let r = std::thread::Builder::new().stack_size(8 * 1024).spawn(f);
+    if let Err(e) = r {
+        log::error!("Failed to start Wi-Fi task: {:?}", e);
+    }
+}
 
 pub fn bt(
-    setting: Arc<Mutex<(super::Setting, esp_idf_svc::nvs::EspDefaultNvs)>>,
+    setting: SharedSetting,
+    modem: SharedModem,
+    sysloop: EspSystemEventLoop,
 ) -> anyhow::Result<()> {
     let ble_device = esp32_nimble::BLEDevice::take();
     let ble_addr = ble_device.get_addr()?.to_string();
@@ -46,7 +358,7 @@ pub fn bt(
         .on_read(move |c, _| {
             log::info!("Read from SSID characteristic");
             let setting = setting1.lock().unwrap();
-            c.set_value(setting.0.ssid.as_bytes());
+            c.set_value(setting.ssid.as_bytes());
         })
         .on_write(move |args| {
             log::info!(
@@ -56,11 +368,9 @@ pub fn bt(
             );
             if let Ok(new_ssid) = String::from_utf8(args.recv_data().to_vec()) {
                 log::info!("New SSID: {}", new_ssid);
-                let mut setting = setting2.lock().unwrap();
-                if let Err(e) = setting.1.set_str("ssid", &new_ssid) {
-                    log::error!("Failed to save SSID to NVS: {:?}", e);
-                } else {
-                    setting.0.ssid = new_ssid;
+                if let Err(e) = setting2.lock().unwrap().update(|s| s.ssid = new_ssid) {
+                    log::error!("Failed to save SSID: {:?}", e);
+                    args.reject();
                 }
             } else {
                 log::error!("Failed to parse new SSID from bytes.");
@@ -77,7 +387,7 @@ pub fn bt(
         .on_read(move |c, _| {
             log::info!("Read from pass characteristic");
             let setting = setting1.lock().unwrap();
-            c.set_value(setting.0.pass.as_bytes());
+            c.set_value(setting.pass.as_bytes());
         })
         .on_write(move |args| {
             log::info!(
@@ -87,17 +397,350 @@ pub fn bt(
             );
             if let Ok(new_pass) = String::from_utf8(args.recv_data().to_vec()) {
                 log::info!("New pass: {}", new_pass);
-                let mut setting = setting2.lock().unwrap();
-                if let Err(e) = setting.1.set_str("pass", &new_pass) {
-                    log::error!("Failed to save pass to NVS: {:?}", e);
-                } else {
-                    setting.0.pass = new_pass;
+                if let Err(e) = setting2.lock().unwrap().update(|s| s.pass = new_pass) {
+                    log::error!("Failed to save pass: {:?}", e);
+                    args.reject();
                 }
             } else {
                 log::error!("Failed to parse new pass from bytes.");
             }
         });
 
+    let setting1 = setting.clone();
+    let setting2 = setting.clone();
+    let auth_characteristic = service
+        .lock()
+        .create_characteristic(AUTH_ID, NimbleProperties::READ | NimbleProperties::WRITE);
+    auth_characteristic
+        .lock()
+        .on_read(move |c, _| {
+            log::info!("Read from auth characteristic");
+            let setting = setting1.lock().unwrap();
+            c.set_value(setting.auth.as_str().as_bytes());
+        })
+        .on_write(move |args| {
+            let Some(auth) = std::str::from_utf8(args.recv_data())
+                .ok()
+                .and_then(crate::network::Auth::parse)
+            else {
+                log::error!("Invalid auth: {:?}", args.recv_data());
+                args.reject();
+                return;
+            };
+            log::info!("New auth: {:?}", auth);
+            if let Err(e) = setting2.lock().unwrap().update(|s| s.auth = auth) {
+                log::error!("Failed to save auth: {:?}", e);
+                args.reject();
+            }
+        });
+
+    let setting1 = setting.clone();
+    let setting2 = setting.clone();
+    let identity_characteristic = service.lock().create_characteristic(
+        IDENTITY_ID,
+        NimbleProperties::READ | NimbleProperties::WRITE,
+    );
+    identity_characteristic
+        .lock()
+        .on_read(move |c, _| {
+            log::info!("Read from identity characteristic");
+            let setting = setting1.lock().unwrap();
+            c.set_value(setting.identity.as_bytes());
+        })
+        .on_write(move |args| {
+            if let Ok(new_identity) = String::from_utf8(args.recv_data().to_vec()) {
+                log::info!("New identity: {}", new_identity);
+                if let Err(e) = setting2
+                    .lock()
+                    .unwrap()
+                    .update(|s| s.identity = new_identity)
+                {
+                    log::error!("Failed to save identity: {:?}", e);
+                    args.reject();
+                }
+            } else {
+                log::error!("Failed to parse new identity from bytes.");
+            }
+        });
+
+    let setting1 = setting.clone();
+    let setting2 = setting.clone();
+    let username_characteristic = service.lock().create_characteristic(
+        USERNAME_ID,
+        NimbleProperties::READ | NimbleProperties::WRITE,
+    );
+    username_characteristic
+        .lock()
+        .on_read(move |c, _| {
+            log::info!("Read from username characteristic");
+            let setting = setting1.lock().unwrap();
+            c.set_value(setting.username.as_bytes());
+        })
+        .on_write(move |args| {
+            if let Ok(new_username) = String::from_utf8(args.recv_data().to_vec()) {
+                log::info!("New username: {}", new_username);
+                if let Err(e) = setting2
+                    .lock()
+                    .unwrap()
+                    .update(|s| s.username = new_username)
+                {
+                    log::error!("Failed to save username: {:?}", e);
+                    args.reject();
+                }
+            } else {
+                log::error!("Failed to parse new username from bytes.");
+            }
+        });
+
+    let setting1 = setting.clone();
+    let setting2 = setting.clone();
+    let diag_characteristic = service
+        .lock()
+        .create_characteristic(DIAG_ID, NimbleProperties::READ | NimbleProperties::WRITE);
+    diag_characteristic
+        .lock()
+        .on_read(move |c, _| {
+            log::info!("Read from diag characteristic");
+            let setting = setting1.lock().unwrap();
+            c.set_value(if setting.diag { b"1" } else { b"0" });
+        })
+        .on_write(move |args| {
+            let enable = match args.recv_data() {
+                b"0" => false,
+                b"1" => true,
+                data => {
+                    log::error!("Invalid diag: {:?}", data);
+                    args.reject();
+                    return;
+                }
+            };
+            log::info!("New diag: {}", enable);
+            if let Err(e) = setting2.lock().unwrap().update(|s| s.diag = enable) {
+                log::error!("Failed to save diag: {:?}", e);
+                args.reject();
+            }
+        });
+
+    let mut transfer = crate::transfer::Receiver::new();
+    let mut transfer_sink = TransferSink {
+        setting: setting.clone(),
+        buf: Vec::new(),
+    };
+    let (frame_tx, frame_rx) = std::sync::mpsc::channel::<Vec<u8>>();
+    let transfer_characteristic = service.lock().create_characteristic(
+        TRANSFER_ID,
+        NimbleProperties::WRITE | NimbleProperties::NOTIFY,
+    );
+    transfer_characteristic.lock().on_write(move |args| {
+        let _ = frame_tx.send(args.recv_data().to_vec());
+    });
+    // Finishing a transfer converts the hello audio, writes files or NVS,
+    // which would stall the BLE host task, see `dfu_service`.
+    let r = std::thread::Builder::new()
+        .stack_size(16 * 1024)
+        .spawn(move || {
+            for frame in frame_rx {
+                let ack = transfer.handle(&frame, &mut transfer_sink);
+                transfer_characteristic
+                    .lock()
+                    .set_value(&ack.to_bytes())
+                    .notify();
+            }
+        });
+    if let Err(e) = r {
+        log::error!("Failed to start transfer task: {:?}", e);
+    }
+
+    let setting1 = setting.clone();
+    let setting2 = setting.clone();
+    let networks_characteristic = service.lock().create_characteristic(
+        NETWORKS_ID,
+        NimbleProperties::READ | NimbleProperties::WRITE,
+    );
+    networks_characteristic
+        .lock()
+        .on_read(move |c, _| {
+            log::info!("Read from networks characteristic");
+            let setting = setting1.lock().unwrap();
+            let networks: Vec<_> = setting
+                .networks
+                .iter()
+                .map(|n| {
+                    serde_json::json!({
+                        "ssid": n.ssid,
+                        "priority": n.priority,
+                        "auth": n.auth,
+                        "ip": n.ip_config.map(|c| c.ip),
+                    })
+                })
+                .collect();
+            c.set_value(serde_json::Value::from(networks).to_string().as_bytes());
+        })
+        .on_write(move |args| {
+            if let Err(e) = setting2.lock().unwrap().update_networks(args.recv_data()) {
+                log::error!("Failed to update networks: {:?}", e);
+                args.reject();
+            }
+        });
+
+    let setting1 = setting.clone();
+    let setting2 = setting.clone();
+    let ip_config_characteristic = service.lock().create_characteristic(
+        IP_CONFIG_ID,
+        NimbleProperties::READ | NimbleProperties::WRITE,
+    );
+    ip_config_characteristic
+        .lock()
+        .on_read(move |c, _| {
+            log::info!("Read from ip config characteristic");
+            let setting = setting1.lock().unwrap();
+            match &setting.ip_config {
+                Some(ip_config) => {
+                    c.set_value(serde_json::to_string(ip_config).unwrap().as_bytes())
+                }
+                None => c.set_value(b""),
+            };
+        })
+        .on_write(move |args| {
+            if let Err(e) = setting2.lock().unwrap().update_ip_config(args.recv_data()) {
+                log::error!("Failed to update ip config: {:?}", e);
+                args.reject();
+            }
+        });
+
+    let setting1 = setting.clone();
+    let setting2 = setting.clone();
+    let panels_characteristic = service
+        .lock()
+        .create_characteristic(PANELS_ID, NimbleProperties::READ | NimbleProperties::WRITE);
+    panels_characteristic
+        .lock()
+        .on_read(move |c, _| {
+            log::info!("Read from panels characteristic");
+            let panels = setting1.lock().unwrap().panels;
+            c.set_value(serde_json::to_string(&panels).unwrap().as_bytes());
+        })
+        .on_write(move |args| {
+            let r = serde_json::from_slice(args.recv_data())
+                .map_err(anyhow::Error::from)
+                .and_then(|panels| setting2.lock().unwrap().update(|s| s.panels = panels));
+            if let Err(e) = r {
+                log::error!("Failed to update panels: {:?}", e);
+                args.reject();
+            }
+        });
+
+    let setting1 = setting.clone();
+    let setting2 = setting.clone();
+    let theme_characteristic = service
+        .lock()
+        .create_characteristic(THEME_ID, NimbleProperties::READ | NimbleProperties::WRITE);
+    theme_characteristic
+        .lock()
+        .on_read(move |c, _| {
+            log::info!("Read from theme characteristic");
+            let theme = setting1.lock().unwrap().theme;
+            c.set_value(serde_json::to_string(&theme).unwrap().as_bytes());
+        })
+        .on_write(move |args| {
+            let r = serde_json::from_slice::<crate::ui::ThemeChoice>(args.recv_data())
+                .map_err(anyhow::Error::from)
+                .and_then(|choice| setting2.lock().unwrap().update_theme(choice));
+            if let Err(e) = r {
+                log::error!("Failed to update theme: {:?}", e);
+                args.reject();
+            }
+        });
+
+    let status_characteristic = service
+        .lock()
+        .create_characteristic(STATUS_ID, NimbleProperties::READ | NimbleProperties::NOTIFY);
+    status_characteristic.lock().set_value(
+        serde_json::json!({ "state": "idle" })
+            .to_string()
+            .as_bytes(),
+    );
+
+    let scan_characteristic = service
+        .lock()
+        .create_characteristic(SCAN_ID, NimbleProperties::READ | NimbleProperties::NOTIFY);
+    scan_characteristic.lock().set_value(b"[]");
+
+    {
+        let modem = modem.clone();
+        let sysloop = sysloop.clone();
+        let scan = scan_characteristic.clone();
+        spawn_wifi_task(move || scan_wifi(&modem, sysloop, &scan));
+    }
+
+    let export = std::sync::Arc::new(Mutex::new((Vec::new(), 0)));
+    let export_ = export.clone();
+    let setting1 = setting.clone();
+    let export_characteristic = service
+        .lock()
+        .create_characteristic(EXPORT_ID, NimbleProperties::READ | NimbleProperties::WRITE);
+    export_characteristic
+        .lock()
+        .on_read(move |c, _| {
+            let (blob, offset) = &*export.lock().unwrap();
+            let end = blob.len().min(offset + EXPORT_PAGE_SIZE);
+            c.set_value(&blob[*offset..end]);
+        })
+        .on_write(move |args| {
+            let Ok(offset) = <[u8; 4]>::try_from(args.recv_data()) else {
+                args.reject();
+                return;
+            };
+            let offset = u32::from_le_bytes(offset) as usize;
+            let mut export = export_.lock().unwrap();
+            if offset == 0 {
+                match setting1.lock().unwrap().export() {
+                    Ok(blob) => *export = (blob, 0),
+                    Err(e) => {
+                        log::error!("Failed to export settings: {:?}", e);
+                        args.reject();
+                    }
+                }
+            } else if offset <= export.0.len() {
+                export.1 = offset;
+            } else {
+                args.reject();
+            }
+        });
+
+    let setting_cmd = setting.clone();
+    let command_characteristic = service
+        .lock()
+        .create_characteristic(COMMAND_ID, NimbleProperties::WRITE);
+    command_characteristic
+        .lock()
+        .on_write(move |args| match args.recv_data() {
+            b"test_wifi" => {
+                let setting = setting_cmd.clone();
+                let modem = modem.clone();
+                let sysloop = sysloop.clone();
+                let status = status_characteristic.clone();
+                spawn_wifi_task(move || test_wifi(&setting, &modem, sysloop, &status));
+            }
+            b"scan" => {
+                let modem = modem.clone();
+                let sysloop = sysloop.clone();
+                let scan = scan_characteristic.clone();
+                spawn_wifi_task(move || scan_wifi(&modem, sysloop, &scan));
+            }
+            b"factory_reset" => match setting_cmd.lock().unwrap().factory_reset() {
+                Ok(()) => restart_soon(),
+                Err(e) => {
+                    log::error!("Factory reset failed: {:?}", e);
+                    args.reject();
+                }
+            },
+            cmd => {
+                log::warn!("Unknown command: {:?}", String::from_utf8_lossy(cmd));
+                args.reject();
+            }
+        });
+
     let setting = setting.clone();
     let setting_ = setting.clone();
     let setting_gif = setting.clone();
@@ -111,7 +754,7 @@ pub fn bt(
         .on_read(move |c, _| {
             log::info!("Read from server URL characteristic");
             let setting = setting.lock().unwrap();
-            c.set_value(setting.0.server_url.as_bytes());
+            c.set_value(setting.server_url.as_bytes());
         })
         .on_write(move |args| {
             log::info!(
@@ -119,22 +762,22 @@ pub fn bt(
                 args.current_data(),
                 args.recv_data()
             );
-            if let Ok(mut new_server_url) = String::from_utf8(args.recv_data().to_vec()) {
+            if let Ok(new_server_url) = String::from_utf8(args.recv_data().to_vec()) {
                 log::info!("New server URL: {}", new_server_url);
-                if !new_server_url.ends_with("/") {
-                    new_server_url.push('/');
-                }
-                let mut setting = setting_.lock().unwrap();
-                if let Err(e) = setting.1.set_str("server_url", &new_server_url) {
-                    log::error!("Failed to save server URL to NVS: {:?}", e);
-                } else {
-                    setting.0.server_url = new_server_url;
+                if let Err(e) = setting_
+                    .lock()
+                    .unwrap()
+                    .update(|s| s.server_url = new_server_url)
+                {
+                    log::error!("Failed to save server URL: {:?}", e);
+                    args.reject();
                 }
             } else {
                 log::error!("Failed to parse new server URL from bytes.");
             }
         });
 
+    // Unframed upload used by older setup pages, superseded by TRANSFER_ID.
     let background_gif_characteristic = service
         .lock()
         .create_characteristic(BACKGROUND_GIF_ID, NimbleProperties::WRITE);
@@ -144,15 +787,17 @@ pub fn bt(
         if gif_chunk.len() <= 1024 * 1024 && gif_chunk.len() > 0 {
             log::info!("New background GIF received, size: {}", gif_chunk.len());
             let mut setting = setting_gif.lock().unwrap();
-            setting.0.background_gif.0.extend_from_slice(gif_chunk);
+            setting.background_gif.0.extend_from_slice(gif_chunk);
             if gif_chunk.len() < 512 {
-                setting.0.background_gif.1 = true; // Mark as valid
+                setting.background_gif.1 = true; // Mark as valid
             }
         } else {
             log::error!("Failed to parse new background GIF from bytes.");
         }
     });
 
+    dfu_service(server);
+
     ble_advertising.lock().set_data(
         BLEAdvertisementData::new()
             .name(&format!("GAIA-ESP32-{}", ble_addr))
@@ -0,0 +1,239 @@
+use std::collections::VecDeque;
+use std::io::{Read, Write as _};
+use std::sync::mpsc::{sync_channel, SyncSender, TrySendError};
+use std::sync::Mutex;
+
+use esp_idf_svc::http::server::{Configuration, EspHttpServer};
+use esp_idf_svc::http::Method;
+use esp_idf_svc::io::Write;
+
+use crate::audio::SAMPLE_RATE;
+
+const BYTES_PER_SEC: usize = 2 * SAMPLE_RATE as usize;
+
+/// Number of utterances kept on flash, older ones are overwritten.
+const MAX_UTTERANCES: usize = 5;
+/// Raw I2S input kept in memory while waiting for an utterance to end.
+const RAW_WINDOW: usize = 6 * BYTES_PER_SEC;
+/// Upper bound of a single AFE recording.
+const MAX_AFE: usize = 10 * BYTES_PER_SEC;
+/// Raw input saved in front of the detected speech, covers the VAD delay.
+const RAW_MARGIN: usize = BYTES_PER_SEC;
+
+const DIAG_DIR: &str = "diag";
+
+struct Inner {
+    raw: VecDeque<u8>,
+    afe: Vec<u8>,
+    next_slot: usize,
+}
+
+/// A finished utterance on its way to flash.
+struct Utterance {
+    slot: usize,
+    raw: Vec<u8>,
+    afe: Vec<u8>,
+}
+
+/// Keeps the raw microphone input and the AFE output of the last
+/// [`MAX_UTTERANCES`] utterances as WAV files on the storage partition.
+pub struct Recorder {
+    inner: Mutex<Inner>,
+    /// Writing takes a while, so it is left to a thread of its own and the
+    /// AFE keeps being drained meanwhile.
+    writer: SyncSender<Utterance>,
+}
+
+fn dir() -> String {
+    crate::storage::path(DIAG_DIR)
+}
+
+fn slot_path(slot: usize, kind: &str) -> String {
+    format!("{}/{}_{}.wav", dir(), slot, kind)
+}
+
+fn index_path() -> String {
+    format!("{}/index", dir())
+}
+
+impl Recorder {
+    pub fn new() -> anyhow::Result<Self> {
+        std::fs::create_dir_all(dir())?;
+        let next_slot = std::fs::read_to_string(index_path())
+            .ok()
+            .and_then(|s| s.trim().parse::<usize>().ok())
+            .unwrap_or(0)
+            % MAX_UTTERANCES;
+
+        // One utterance waits while another is written, later ones are
+        // dropped rather than piling up in memory.
+        let (writer, rx) = sync_channel::<Utterance>(1);
+        std::thread::Builder::new()
+            .name("diag_writer".to_string())
+            .stack_size(8 * 1024)
+            .spawn(move || {
+                for utterance in rx {
+                    save(utterance);
+                }
+            })?;
+        log::info!("Diagnostic recorder ready, next slot {}", next_slot);
+
+        Ok(Self {
+            inner: Mutex::new(Inner {
+                raw: VecDeque::with_capacity(RAW_WINDOW),
+                afe: Vec::with_capacity(MAX_AFE),
+                next_slot,
+            }),
+            writer,
+        })
+    }
+
+    pub fn feed_raw(&self, data: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        inner.raw.extend(data);
+        let len = inner.raw.len();
+        if len > RAW_WINDOW {
+            inner.raw.drain(..len - RAW_WINDOW);
+        }
+    }
+
+    pub fn feed_afe(&self, data: &[u8]) {
+        let mut inner = self.inner.lock().unwrap();
+        if inner.afe.len() + data.len() <= MAX_AFE {
+            inner.afe.extend_from_slice(data);
+        }
+    }
+
+    /// Hands the current utterance to the writer thread. The raw window is
+    /// cut to the length of the AFE output plus [`RAW_MARGIN`].
+    pub fn finish_utterance(&self) {
+        let utterance = {
+            let mut inner = self.inner.lock().unwrap();
+            if inner.afe.is_empty() {
+                return;
+            }
+            let afe = std::mem::replace(&mut inner.afe, Vec::with_capacity(MAX_AFE));
+            let raw_len = (afe.len() + RAW_MARGIN).min(inner.raw.len());
+            let skip = inner.raw.len() - raw_len;
+            let raw: Vec<u8> = inner.raw.iter().skip(skip).copied().collect();
+
+            let slot = inner.next_slot;
+            inner.next_slot = (slot + 1) % MAX_UTTERANCES;
+            Utterance { slot, raw, afe }
+        };
+
+        match self.writer.try_send(utterance) {
+            Ok(()) => {}
+            Err(TrySendError::Full(u)) => {
+                log::warn!("Utterance for slot {} dropped, writer busy", u.slot)
+            }
+            Err(TrySendError::Disconnected(_)) => log::error!("Diagnostic writer stopped"),
+        }
+    }
+}
+
+fn save(Utterance { slot, raw, afe }: Utterance) {
+    if let Err(e) = check_space(slot, raw.len(), afe.len()) {
+        log::warn!("Utterance not saved: {:?}", e);
+        return;
+    }
+    let r = write_wav(&slot_path(slot, "raw"), &raw)
+        .and_then(|_| write_wav(&slot_path(slot, "afe"), &afe))
+        .and_then(|_| std::fs::write(index_path(), ((slot + 1) % MAX_UTTERANCES).to_string()));
+    match r {
+        Ok(_) => log::info!(
+            "Saved utterance to slot {} (raw {} bytes, afe {} bytes)",
+            slot,
+            raw.len(),
+            afe.len()
+        ),
+        Err(e) => log::error!("Failed to save utterance: {:?}", e),
+    }
+}
+
+/// Recordings only use the space the assets and the EAP CA certificate
+/// leave, the files of `slot` being overwritten give theirs back.
+fn check_space(slot: usize, raw_len: usize, afe_len: usize) -> anyhow::Result<()> {
+    let reclaimed: u64 = ["raw", "afe"]
+        .iter()
+        .filter_map(|kind| std::fs::metadata(slot_path(slot, kind)).ok())
+        .map(|m| crate::storage::allocated(m.len() as usize))
+        .sum();
+    let needed = crate::storage::allocated(crate::wav::HEADER_LEN + raw_len)
+        + crate::storage::allocated(crate::wav::HEADER_LEN + afe_len);
+    let reserved = crate::assets::headroom()
+        + crate::storage::allocated(
+            crate::storage::CHECKED_HEADER_LEN + crate::network::MAX_EAP_CA_SIZE,
+        );
+    let free = crate::storage::free_space()? + reclaimed;
+    if free < needed + reserved {
+        anyhow::bail!(
+            "{} bytes needed, {} free of which {} are reserved",
+            needed,
+            free,
+            reserved
+        );
+    }
+    Ok(())
+}
+
+fn write_wav(path: &str, data: &[u8]) -> std::io::Result<()> {
+    let mut file = std::fs::File::create(path)?;
+    crate::wav::write_header(&mut file, SAMPLE_RATE, 1, 16, data.len() as u32)?;
+    file.write_all(data)?;
+    Ok(())
+}
+
+/// Starts an HTTP server listing the recordings at `/diag` and serving them
+/// at `/diag/<slot>_raw.wav` and `/diag/<slot>_afe.wav`.
+pub fn start_server() -> anyhow::Result<EspHttpServer<'static>> {
+    let mut server = EspHttpServer::new(&Configuration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+
+    server.fn_handler("/diag", Method::Get, |req| -> anyhow::Result<()> {
+        let mut files = vec![];
+        for entry in std::fs::read_dir(dir())? {
+            let entry = entry?;
+            let name = entry.file_name().to_string_lossy().to_string();
+            if name.ends_with(".wav") {
+                files.push(serde_json::json!({
+                    "name": name,
+                    "size": entry.metadata().map(|m| m.len()).unwrap_or(0),
+                }));
+            }
+        }
+        let body = serde_json::to_vec(&files)?;
+        let mut resp = req.into_response(200, None, &[("Content-Type", "application/json")])?;
+        resp.write_all(&body)?;
+        Ok(())
+    })?;
+
+    server.fn_handler("/diag/*", Method::Get, |req| -> anyhow::Result<()> {
+        let name = req.uri().trim_start_matches("/diag/").to_string();
+        if name.is_empty() || name.contains('/') || !name.ends_with(".wav") {
+            req.into_status_response(404)?;
+            return Ok(());
+        }
+
+        let Ok(mut file) = std::fs::File::open(format!("{}/{}", dir(), name)) else {
+            req.into_status_response(404)?;
+            return Ok(());
+        };
+
+        let mut resp = req.into_response(200, None, &[("Content-Type", "audio/wav")])?;
+        let mut buf = [0u8; 1024];
+        loop {
+            let n = file.read(&mut buf)?;
+            if n == 0 {
+                break;
+            }
+            resp.write_all(&buf[..n])?;
+        }
+        Ok(())
+    })?;
+
+    log::info!("Diagnostic HTTP server started");
+    Ok(server)
+}
@@ -3,25 +3,31 @@ use std::sync::{Arc, Mutex};
 use esp_idf_svc::eventloop::EspSystemEventLoop;
 
 mod app;
+mod assets;
 mod audio;
+mod board;
 mod bt;
+mod diag;
 mod esp32;
-mod hal;
 mod network;
+mod ota;
+mod portal;
 mod protocol;
+mod resample;
+mod settings;
+mod storage;
+mod transfer;
 mod ui;
+mod wav;
 mod wifi_scan;
 mod ws;
 
 slint::include_modules!();
 
-#[derive(Debug, Clone)]
-struct Setting {
-    ssid: String,
-    pass: String,
-    server_url: String,
-    background_gif: (Vec<u8>, bool), // (data, ended)
-}
+type SharedSetting = Arc<Mutex<settings::Store>>;
+
+/// How long K0 has to stay held at boot for a factory reset.
+const FACTORY_RESET_HOLD: std::time::Duration = std::time::Duration::from_secs(10);
 
 fn main() -> anyhow::Result<()> {
     esp_idf_svc::sys::link_patches();
@@ -34,10 +40,12 @@ fn main() -> anyhow::Result<()> {
     log_heap();
 
     log::info!("Initializing audio...");
-    crate::hal::audio_init();
+    crate::board::init_codec();
 
     let modem = peripherals.modem;
-    wifi_scan::scan(modem, sysloop);
+    if let Err(e) = wifi_scan::scan(modem, sysloop) {
+        log::error!("WiFi scan failed: {:?}", e);
+    }
 
     // log::info!("Initializing UI...");
     // ui::lcd_init().unwrap();
@@ -113,44 +121,39 @@ fn setup() -> anyhow::Result<()> {
     let _fs = esp_idf_svc::io::vfs::MountedEventfs::mount(20)?;
     let partition = esp_idf_svc::nvs::EspDefaultNvsPartition::take()?;
     let nvs = esp_idf_svc::nvs::EspDefaultNvs::new(partition, "setting", true)?;
+    if let Err(e) = storage::mount() {
+        log::error!("Failed to mount storage: {:?}", e);
+    } else if let Err(e) = assets::init() {
+        log::error!("Failed to create asset directory: {:?}", e);
+    }
 
     log_heap();
 
-    crate::hal::audio_init();
-    ui::lcd_init().unwrap();
+    let board::Parts { audio, mut button } =
+        <board::CurrentBoard as board::Board>::init(peripherals.i2s0, peripherals.i2s1)?;
 
     log_heap();
-    let mut ssid_buf = [0; 32];
-    let ssid = nvs
-        .get_str("ssid", &mut ssid_buf)
-        .map_err(|e| log::error!("Failed to get ssid: {:?}", e))
-        .ok()
-        .flatten();
-
-    let mut pass_buf = [0; 64];
-    let pass = nvs
-        .get_str("pass", &mut pass_buf)
-        .map_err(|e| log::error!("Failed to get pass: {:?}", e))
-        .ok()
-        .flatten();
-
-    let mut server_url = [0; 128];
-    let server_url = nvs
-        .get_str("server_url", &mut server_url)
-        .map_err(|e| log::error!("Failed to get server_url: {:?}", e))
-        .ok()
-        .flatten();
-
-    // 1MB buffer for GIF
-    let mut gif_buf = vec![0; 1024 * 1024];
-    let background_gif = nvs.get_blob("background_gif", &mut gif_buf)?;
-
-    log::info!("SSID: {:?}", ssid);
-    log::info!("PASS: {:?}", pass);
-    log::info!("Server URL: {:?}", server_url);
+    let store = settings::Store::open(nvs)?;
+
+    let background_gif = assets::get(assets::BACKGROUND_GIF, assets::MAX_BACKGROUND_GIF_SIZE);
+
+    log::info!("SSID: {:?}", store.ssid);
+    log::info!("PASS: {:?}", store.pass);
+    log::info!("Auth: {:?} (username {:?})", store.auth, store.username);
+    log::info!("IP config: {:?}", store.ip_config);
+    for network in &store.networks {
+        log::info!(
+            "Known network: {} (priority {})",
+            network.ssid,
+            network.priority
+        );
+    }
+    log::info!("Server URL: {:?}", store.server_url);
+    log::info!("Diagnostic recording: {}", store.diag);
+    let diag = store.diag;
 
     log_heap();
-    if let Some(background_gif) = background_gif {
+    if let Some(background_gif) = &background_gif {
         let _ = ui::backgroud(&background_gif);
     } else {
         let mut ui = ui::UI::new(None).unwrap();
@@ -162,67 +165,123 @@ fn setup() -> anyhow::Result<()> {
         }
     }
 
-    // Configures the button
-    let mut button = esp_idf_svc::hal::gpio::PinDriver::input(peripherals.pins.gpio0)?;
-    button.set_pull(esp_idf_svc::hal::gpio::Pull::Up)?;
-    button.set_interrupt_type(esp_idf_svc::hal::gpio::InterruptType::PosEdge)?;
-
     let b = tokio::runtime::Builder::new_current_thread()
         .enable_all()
         .build()?;
 
     let mut gui = ui::UI::new(None).unwrap();
 
-    let setting = Arc::new(Mutex::new((
-        Setting {
-            ssid: ssid.unwrap_or_default().to_string(),
-            pass: pass.unwrap_or_default().to_string(),
-            server_url: server_url.unwrap_or_default().to_string(),
-            background_gif: (Vec::with_capacity(1024 * 1024), false), // 1MB
-        },
-        nvs,
-    )));
+    let setting = Arc::new(Mutex::new(store));
 
     log_heap();
 
     let need_init = {
         let setting = setting.lock().unwrap();
-        setting.0.ssid.is_empty()
-            || setting.0.pass.is_empty()
-            || setting.0.server_url.is_empty()
-            || button.is_low()
+        setting.known_networks().is_empty() || setting.server_url.is_empty() || button.is_low()
     };
     if need_init {
-        bt::bt(setting.clone()).unwrap();
+        if button.is_low() {
+            gui.state = "Keep holding K0 for a factory reset".to_string();
+            gui.text = format!(
+                "Release K0 to set up the device\nHold it {} s to erase all settings",
+                FACTORY_RESET_HOLD.as_secs()
+            );
+            gui.display_flush().unwrap();
+            let held = b
+                .block_on(tokio::time::timeout(
+                    FACTORY_RESET_HOLD,
+                    button.wait_for_rising_edge(),
+                ))
+                .is_err();
+            if held && button.is_low() {
+                gui.state = "Factory reset...".to_string();
+                gui.text.clear();
+                gui.display_flush().unwrap();
+                if let Err(e) = setting.lock().unwrap().factory_reset() {
+                    log::error!("Factory reset failed: {:?}", e);
+                    gui.text = format!("Factory reset failed: {e}");
+                    gui.display_flush().unwrap();
+                    std::thread::sleep(std::time::Duration::from_secs(3));
+                }
+                unsafe { esp_idf_svc::sys::esp_restart() }
+            }
+        }
+
+        let mut changes = setting.lock().unwrap().subscribe();
+        let modem = Arc::new(Mutex::new(peripherals.modem));
+        bt::bt(setting.clone(), modem.clone(), sysloop.clone()).unwrap();
+        // The DFU service is up, so the firmware can update itself again even
+        // without a server.
+        ota::mark_valid();
         log_heap();
 
         gui.state = "Please setup device by bt".to_string();
-        gui.text = "Goto https://echokit.dev/setup/ to set up the device.\nPress K0 to continue"
+        gui.text = "Goto https://echokit.dev/setup/ to set up the device.\nPress K0 to continue\nHold K0 to set up over Wi-Fi"
             .to_string();
-        gui.display_qrcode("https://echokit.dev/setup/").unwrap();
-        b.block_on(button.wait_for_falling_edge()).unwrap();
+        let mut qrcode = "https://echokit.dev/setup/".to_string();
+        gui.display_qrcode(&qrcode).unwrap();
+        let mut portal_started = false;
+        loop {
+            let changed = b.block_on(async {
+                tokio::select! {
+                    r = button.wait_for_falling_edge() => {
+                        r.unwrap();
+                        None
+                    }
+                    Some(key) = changes.recv() => Some(key),
+                }
+            });
+            if let Some(key) = changed {
+                gui.state = format!("Saved {key}");
+                gui.display_qrcode(&qrcode).unwrap();
+                continue;
+            }
+            let held = b
+                .block_on(tokio::time::timeout(
+                    std::time::Duration::from_secs(1),
+                    button.wait_for_rising_edge(),
+                ))
+                .is_err();
+            if !held || portal_started {
+                break;
+            }
+
+            match portal::spawn(setting.clone(), modem.clone(), sysloop.clone()) {
+                Ok((ssid, pass, ip)) => {
+                    portal_started = true;
+                    gui.state = "Please setup device by Wi-Fi".to_string();
+                    gui.text = format!(
+                        "Join the Wi-Fi network {ssid}, password {pass}, and open http://{ip}/\nPress K0 to continue"
+                    );
+                    qrcode = format!("WIFI:T:WPA;S:{ssid};P:{pass};;");
+                    gui.display_qrcode(&qrcode).unwrap();
+                }
+                Err(e) => {
+                    log::error!("Failed to start setup access point: {:?}", e);
+                    gui.text = format!("Failed to start Wi-Fi setup: {e}\nPress K0 to continue");
+                    gui.display_flush().unwrap();
+                }
+            }
+        }
         {
             let mut setting = setting.lock().unwrap();
-            if setting.0.background_gif.1 {
+            if setting.background_gif.1 {
                 gui.text = "Testing background GIF...".to_string();
                 gui.display_flush().unwrap();
 
                 let mut new_gif = Vec::new();
-                std::mem::swap(&mut setting.0.background_gif.0, &mut new_gif);
+                std::mem::swap(&mut setting.background_gif.0, &mut new_gif);
 
                 let _ = ui::backgroud(&new_gif);
-                log::info!("Background GIF set from NVS");
+                log::info!("Background GIF tested");
 
                 gui.text = "Background GIF set OK".to_string();
                 gui.display_flush().unwrap();
 
                 if !new_gif.is_empty() {
-                    setting
-                        .1
-                        .set_blob("background_gif", &new_gif)
-                        .map_err(|e| log::error!("Failed to save background GIF to NVS: {:?}", e))
-                        .unwrap();
-                    log::info!("Background GIF saved to NVS");
+                    if let Err(e) = assets::put(assets::BACKGROUND_GIF, &new_gif) {
+                        log::error!("Failed to save background GIF: {:?}", e);
+                    }
                 }
             }
         }
@@ -237,8 +296,7 @@ fn setup() -> anyhow::Result<()> {
     let _wifi = {
         let setting = setting.lock().unwrap();
         network::wifi(
-            &setting.0.ssid,
-            &setting.0.pass,
+            &setting.known_networks(),
             peripherals.modem,
             sysloop.clone(),
         )
@@ -259,49 +317,36 @@ fn setup() -> anyhow::Result<()> {
         "{:02x}{:02x}{:02x}{:02x}{:02x}{:02x}",
         mac[0], mac[1], mac[2], mac[3], mac[4], mac[5]
     );
+    let ip = wifi.sta_netif().get_ip_info()?.ip;
 
-    let (evt_tx, evt_rx) = tokio::sync::mpsc::channel(64);
-    let (tx1, rx1) = tokio::sync::mpsc::unbounded_channel();
+    let networks = setting.lock().unwrap().known_networks();
+    let (_reconnect, link) = network::keep_connected(wifi, networks, &sysloop)?;
 
-    #[cfg(feature = "box")]
-    let i2s_task = {
-        let bclk = peripherals.pins.gpio21;
-        let din = peripherals.pins.gpio47;
-        let dout = peripherals.pins.gpio14;
-        let ws = peripherals.pins.gpio13;
-
-        audio::i2s_task(
-            peripherals.i2s0,
-            bclk.into(),
-            din.into(),
-            dout.into(),
-            ws.into(),
-            (evt_tx.clone(), rx1),
-        )
+    let recorder = if diag {
+        match diag::Recorder::new() {
+            Ok(recorder) => Some(Arc::new(recorder)),
+            Err(e) => {
+                log::error!("Failed to start diagnostic recorder: {:?}", e);
+                None
+            }
+        }
+    } else {
+        None
     };
-
-    #[cfg(feature = "boards")]
-    let i2s_task = {
-        let sck = peripherals.pins.gpio5;
-        let din = peripherals.pins.gpio6;
-        let dout = peripherals.pins.gpio7;
-        let ws = peripherals.pins.gpio4;
-        let bclk = peripherals.pins.gpio15;
-        let lrclk = peripherals.pins.gpio16;
-
-        audio::i2s_task_(
-            peripherals.i2s0,
-            ws.into(),
-            sck.into(),
-            din.into(),
-            peripherals.i2s1,
-            bclk.into(),
-            lrclk.into(),
-            dout.into(),
-            (evt_tx.clone(), rx1),
-        )
+    let _diag_server = if recorder.is_some() {
+        log::info!("Diagnostic recordings at http://{}/diag", ip);
+        diag::start_server()
+            .map_err(|e| log::error!("Failed to start diagnostic server: {:?}", e))
+            .ok()
+    } else {
+        None
     };
 
+    let (evt_tx, evt_rx) = tokio::sync::mpsc::channel(64);
+    let (tx1, rx1) = tokio::sync::mpsc::unbounded_channel();
+
+    let i2s_task = audio::i2s_task(audio, (evt_tx.clone(), rx1), recorder);
+
     gui.state = "Connecting to server...".to_string();
     gui.text.clear();
     gui.display_flush().unwrap();
@@ -310,20 +355,33 @@ fn setup() -> anyhow::Result<()> {
 
     let server_url = {
         let setting = setting.lock().unwrap();
-        format!("{}{}", setting.0.server_url, mac_str)
+        format!("{}{}", setting.server_url, mac_str)
     };
     let server = b.block_on(ws::Server::new(server_url.clone()));
     if server.is_err() {
         gui.state = "Failed to connect to server".to_string();
         gui.text = format!("Please check your server URL: {server_url}");
         gui.display_flush().unwrap();
+        for line in network::diagnose(&server_url) {
+            log::info!("Diagnostics: {}", line);
+            gui.text.push('\n');
+            gui.text.push_str(&line);
+        }
+        gui.display_flush().unwrap();
         b.block_on(button.wait_for_falling_edge()).unwrap();
         unsafe { esp_idf_svc::sys::esp_restart() }
     }
 
     let server = server.unwrap();
 
-    let ws_task = app::main_work(server, tx1, evt_rx, background_gif);
+    let ws_task = app::main_work(
+        server,
+        tx1,
+        evt_rx,
+        link,
+        setting.clone(),
+        background_gif.as_deref(),
+    );
 
     b.spawn(async move {
         loop {
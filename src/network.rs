@@ -1,59 +1,512 @@
+use std::net::{Ipv4Addr, SocketAddr, TcpStream, ToSocketAddrs};
+use std::time::Duration;
+
 use esp_idf_svc::{
-    eventloop::EspSystemEventLoop,
+    eventloop::{EspSubscription, EspSystemEventLoop, System},
     hal::peripheral,
     http::{client::EspHttpConnection, Method},
-    wifi::{AuthMethod, BlockingWifi, EspWifi},
+    ipv4,
+    netif::{EspNetif, IpEvent, NetifConfiguration, NetifStack},
+    sys::esp,
+    wifi::{AuthMethod, BlockingWifi, EspWifi, WifiEvent},
 };
 use log::info;
 
-pub fn wifi(
-    ssid: &str,
-    pass: &str,
-    modem: impl peripheral::Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'static,
-    sysloop: EspSystemEventLoop,
-) -> anyhow::Result<Box<EspWifi<'static>>> {
-    let mut auth_method = AuthMethod::WPA2Personal;
-    if ssid.is_empty() {
-        anyhow::bail!("Missing WiFi name")
+/// The modem shared by the provisioning services, which use it one at a time.
+pub type SharedModem = std::sync::Arc<std::sync::Mutex<esp_idf_svc::hal::modem::Modem>>;
+
+/// Fixed address of the STA interface, DHCP is used when it is not set.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct IpConfig {
+    pub ip: Ipv4Addr,
+    /// Netmask length, 24 for 255.255.255.0.
+    pub prefix: u8,
+    pub gateway: Ipv4Addr,
+    #[serde(default)]
+    pub dns: Option<Ipv4Addr>,
+    #[serde(default)]
+    pub secondary_dns: Option<Ipv4Addr>,
+}
+
+impl IpConfig {
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(1..=30).contains(&self.prefix) {
+            anyhow::bail!("Invalid netmask length {}", self.prefix);
+        }
+        let mask = u32::MAX << (32 - self.prefix);
+        if u32::from(self.ip) & mask != u32::from(self.gateway) & mask {
+            anyhow::bail!(
+                "Gateway {} is not in {}/{}",
+                self.gateway,
+                self.ip,
+                self.prefix
+            );
+        }
+        Ok(())
     }
-    if pass.is_empty() {
-        auth_method = AuthMethod::None;
-        info!("Wifi password is empty");
+
+    fn sta_netif_conf(&self) -> NetifConfiguration {
+        NetifConfiguration {
+            ip_configuration: Some(ipv4::Configuration::Client(
+                ipv4::ClientConfiguration::Fixed(ipv4::ClientSettings {
+                    ip: self.ip,
+                    subnet: ipv4::Subnet {
+                        gateway: self.gateway,
+                        mask: ipv4::Mask(self.prefix),
+                    },
+                    dns: self.dns,
+                    secondary_dns: self.secondary_dns,
+                }),
+            )),
+            ..NetifConfiguration::wifi_default_client()
+        }
     }
-    let mut esp_wifi = EspWifi::new(modem, sysloop.clone(), None)?;
+}
 
-    let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop)?;
+/// Most networks kept in the settings.
+pub const MAX_KNOWN_NETWORKS: usize = 5;
+
+/// Stored and exchanged by the names of [`Auth::as_str`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Auth {
+    /// WPA2-PSK, or an open network when the password is empty.
+    #[default]
+    Personal,
+    /// WPA3-SAE.
+    Wpa3,
+    /// WPA2-Enterprise with PEAP/MSCHAPv2, the password belongs to `username`.
+    Enterprise,
+}
+
+impl Auth {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Auth::Personal => "personal",
+            Auth::Wpa3 => "wpa3",
+            Auth::Enterprise => "enterprise",
+        }
+    }
+
+    pub fn parse(s: &str) -> Option<Self> {
+        match s {
+            "personal" => Some(Auth::Personal),
+            "wpa3" => Some(Auth::Wpa3),
+            "enterprise" => Some(Auth::Enterprise),
+            _ => None,
+        }
+    }
+}
+
+impl serde::Serialize for Auth {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.as_str())
+    }
+}
+
+impl<'de> serde::Deserialize<'de> for Auth {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Auth::parse(&s).ok_or_else(|| {
+            serde::de::Error::unknown_variant(&s, &["personal", "wpa3", "enterprise"])
+        })
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct KnownNetwork {
+    pub ssid: String,
+    #[serde(default)]
+    pub pass: String,
+    /// Higher is tried first.
+    #[serde(default)]
+    pub priority: u8,
+    #[serde(default)]
+    pub auth: Auth,
+    /// Outer EAP identity, `username` when empty.
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub identity: String,
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub username: String,
+    /// Static address on this network, DHCP when not set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ip_config: Option<IpConfig>,
+}
+
+/// CA certificate on the storage partition used to check enterprise
+/// networks, uploaded as a [`crate::transfer::Kind::Cert`].
+pub const EAP_CA_FILE: &str = "eap_ca.pem";
+pub const MAX_EAP_CA_SIZE: usize = 8 * 1024;
+
+/// The EAP client keeps a pointer to the CA certificate instead of a copy.
+static EAP_CA: std::sync::OnceLock<Option<Vec<u8>>> = std::sync::OnceLock::new();
+
+/// Sets up the EAP client before connecting to an enterprise network.
+fn enable_enterprise(network: &KnownNetwork) -> anyhow::Result<()> {
+    use esp_idf_svc::sys::*;
+
+    if network.username.is_empty() {
+        anyhow::bail!("Missing username for {}", network.ssid);
+    }
+    let identity = if network.identity.is_empty() {
+        &network.username
+    } else {
+        &network.identity
+    };
+    unsafe {
+        esp!(esp_eap_client_set_identity(
+            identity.as_ptr(),
+            identity.len() as _
+        ))?;
+        esp!(esp_eap_client_set_username(
+            network.username.as_ptr(),
+            network.username.len() as _
+        ))?;
+        esp!(esp_eap_client_set_password(
+            network.pass.as_ptr(),
+            network.pass.len() as _
+        ))?;
+    }
+
+    let ca = EAP_CA.get_or_init(|| {
+        crate::storage::read_checked(EAP_CA_FILE, MAX_EAP_CA_SIZE).map(|mut ca| {
+            // mbedTLS wants the terminating NUL counted in PEM lengths.
+            ca.push(0);
+            ca
+        })
+    });
+    match ca {
+        Some(ca) => {
+            unsafe { esp!(esp_eap_client_set_ca_cert(ca.as_ptr(), ca.len() as _))? };
+        }
+        None => {
+            log::warn!(
+                "No CA certificate, the server of {} is not verified",
+                network.ssid
+            );
+            unsafe { esp_eap_client_clear_ca_cert() };
+        }
+    }
+
+    unsafe { esp!(esp_wifi_sta_enterprise_enable())? };
+    Ok(())
+}
+
+/// Order in which to try the known networks: the ones seen in the scan by
+/// priority and then signal strength, followed by the ones not seen (they
+/// may be hidden) by priority.
+pub fn rank<'a>(known: &'a [KnownNetwork], visible: &[(&str, i8)]) -> Vec<&'a KnownNetwork> {
+    let rssi = |ssid: &str| {
+        visible
+            .iter()
+            .filter(|(s, _)| *s == ssid)
+            .map(|(_, rssi)| *rssi)
+            .max()
+    };
+    let mut ranked: Vec<(&KnownNetwork, Option<i8>)> =
+        known.iter().map(|n| (n, rssi(&n.ssid))).collect();
+    ranked.sort_by(|(a, a_rssi), (b, b_rssi)| {
+        b_rssi
+            .is_some()
+            .cmp(&a_rssi.is_some())
+            .then(b.priority.cmp(&a.priority))
+            .then(b_rssi.cmp(a_rssi))
+    });
+    ranked.into_iter().map(|(n, _)| n).collect()
+}
+
+fn connect(
+    wifi: &mut BlockingWifi<&mut EspWifi<'_>>,
+    network: &KnownNetwork,
+) -> anyhow::Result<()> {
+    let sta_netif = match &network.ip_config {
+        None => EspNetif::new(NetifStack::Sta)?,
+        Some(ip_config) => {
+            info!("Using static IP {:?} on {}", ip_config, network.ssid);
+            EspNetif::new_with_conf(&ip_config.sta_netif_conf())?
+        }
+    };
+    wifi.wifi_mut().swap_netif_sta(sta_netif)?;
+
+    unsafe { esp_idf_svc::sys::esp_wifi_sta_enterprise_disable() };
+    let (auth_method, password) = match network.auth {
+        Auth::Personal if network.pass.is_empty() => {
+            info!("Wifi password is empty");
+            (AuthMethod::None, "")
+        }
+        Auth::Personal => (AuthMethod::WPA2Personal, network.pass.as_str()),
+        Auth::Wpa3 => (AuthMethod::WPA3Personal, network.pass.as_str()),
+        Auth::Enterprise => {
+            enable_enterprise(network)?;
+            (AuthMethod::WPA2Enterprise, "")
+        }
+    };
 
     wifi.set_configuration(&esp_idf_svc::wifi::Configuration::Client(
         esp_idf_svc::wifi::ClientConfiguration {
-            ssid: ssid
+            ssid: network
+                .ssid
+                .as_str()
                 .try_into()
-                .expect("Could not parse the given SSID into WiFi config"),
-            password: pass
+                .map_err(|_| anyhow::anyhow!("Invalid SSID: {}", network.ssid))?,
+            password: password
                 .try_into()
-                .expect("Could not parse the given password into WiFi config"),
+                .map_err(|_| anyhow::anyhow!("Invalid password for {}", network.ssid))?,
             auth_method,
             ..Default::default()
         },
     ))?;
 
-    wifi.start()?;
-
-    info!("Connecting wifi...");
+    info!("Connecting wifi {}...", network.ssid);
 
     wifi.connect()?;
 
     info!("Waiting for DHCP lease...");
 
     wifi.wait_netif_up()?;
+    Ok(())
+}
+
+/// Connects to the best of `networks`, falling back to the next candidate
+/// when a connection fails. Each candidate gets its own static IP or DHCP.
+pub fn wifi<'d>(
+    networks: &[KnownNetwork],
+    modem: impl peripheral::Peripheral<P = esp_idf_svc::hal::modem::Modem> + 'd,
+    sysloop: EspSystemEventLoop,
+) -> anyhow::Result<Box<EspWifi<'d>>> {
+    if networks.is_empty() {
+        anyhow::bail!("Missing WiFi name")
+    }
+    let mut esp_wifi = EspWifi::new(modem, sysloop.clone(), None)?;
 
-    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
+    let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop)?;
 
-    info!("Wifi DHCP info: {:?}", ip_info);
+    wifi.set_configuration(&esp_idf_svc::wifi::Configuration::Client(Default::default()))?;
+    wifi.start()?;
 
+    connect_best(&mut wifi, networks, CONNECT_ATTEMPTS)?;
     Ok(Box::new(esp_wifi))
 }
 
+/// Tries the ranked `networks` for `rounds` rounds, with backoff between
+/// the rounds.
+fn connect_best(
+    wifi: &mut BlockingWifi<&mut EspWifi<'_>>,
+    networks: &[KnownNetwork],
+    rounds: u32,
+) -> anyhow::Result<()> {
+    let visible = if networks.len() > 1 {
+        wifi.scan().unwrap_or_else(|e| {
+            log::warn!("Wifi scan failed: {:?}", e);
+            vec![]
+        })
+    } else {
+        vec![]
+    };
+    let visible: Vec<(&str, i8)> = visible
+        .iter()
+        .map(|ap| (ap.ssid.as_str(), ap.signal_strength))
+        .collect();
+
+    let mut last_error = None;
+    for attempt in 0..rounds {
+        if attempt > 0 {
+            let delay = backoff(attempt - 1);
+            info!("Retrying wifi in {:?}", delay);
+            std::thread::sleep(delay);
+        }
+        for network in rank(networks, &visible) {
+            match connect(&mut wifi, network) {
+                Ok(()) => {
+                    let ip_info = wifi.wifi().sta_netif().get_ip_info()?;
+                    info!("Wifi DHCP info: {:?}", ip_info);
+                    return Ok(());
+                }
+                Err(e) => {
+                    log::warn!("Failed to connect to {}: {:?}", network.ssid, e);
+                    let _ = wifi.disconnect();
+                    last_error = Some(e);
+                }
+            }
+        }
+    }
+
+    Err(last_error.unwrap_or_else(|| anyhow::anyhow!("Missing WiFi name")))
+}
+
+/// Rounds over all candidates before [`wifi`] gives up.
+const CONNECT_ATTEMPTS: u32 = 4;
+
+/// Failed reconnects to the last network before [`keep_connected`] ranks
+/// and tries all networks again.
+const RECONNECT_ATTEMPTS: u32 = 3;
+
+/// Delay before the retry following `failures` failed ones: 1s, doubling up
+/// to 32s.
+fn backoff(failures: u32) -> Duration {
+    Duration::from_secs(1 << failures.min(5))
+}
+
+/// Whether the STA interface is connected and has an address.
+pub type LinkState = tokio::sync::watch::Receiver<bool>;
+
+/// Keeps the connection established by [`wifi`] up: reconnects with backoff
+/// after a drop, falls back to the other networks when that keeps failing,
+/// and publishes the link state. Dropping it stops both and the Wi-Fi.
+pub struct Reconnect {
+    _wifi: EspSubscription<'static, System>,
+    _ip: EspSubscription<'static, System>,
+}
+
+enum Link {
+    Up,
+    Down,
+}
+
+/// Starts watching the connection of `wifi` to one of `networks`, which
+/// must be up.
+pub fn keep_connected(
+    mut wifi: Box<EspWifi<'static>>,
+    networks: Vec<KnownNetwork>,
+    sysloop: &EspSystemEventLoop,
+) -> anyhow::Result<(Reconnect, LinkState)> {
+    let (state_tx, state_rx) = tokio::sync::watch::channel(true);
+    let (tx, rx) = std::sync::mpsc::channel();
+
+    let tx_ = tx.clone();
+    let wifi_sub = sysloop.subscribe::<WifiEvent, _>(move |event| {
+        if let WifiEvent::StaDisconnected(_) = event {
+            let _ = tx_.send(Link::Down);
+        }
+    })?;
+    let ip_sub = sysloop.subscribe::<IpEvent, _>(move |event| {
+        if let IpEvent::DhcpIpAssigned(_) = event {
+            let _ = tx.send(Link::Up);
+        }
+    })?;
+
+    // Reconnecting waits, which must not happen in the event loop task.
+    let sysloop = sysloop.clone();
+    std::thread::Builder::new()
+        .stack_size(8 * 1024)
+        .spawn(move || {
+            let mut failures = 0;
+            let mut retry = false;
+            loop {
+                let link = if std::mem::take(&mut retry) {
+                    Link::Down
+                } else {
+                    match rx.recv() {
+                        Ok(link) => link,
+                        Err(_) => return,
+                    }
+                };
+                match link {
+                    Link::Up => {
+                        info!("Wifi reconnected");
+                        failures = 0;
+                        state_tx.send_replace(true);
+                    }
+                    Link::Down => {
+                        state_tx.send_replace(false);
+                        let delay = backoff(failures);
+                        log::warn!("Wifi disconnected, reconnecting in {:?}", delay);
+                        std::thread::sleep(delay);
+                        failures += 1;
+                        if failures % RECONNECT_ATTEMPTS != 0 {
+                            if let Err(e) = esp!(unsafe { esp_idf_svc::sys::esp_wifi_connect() }) {
+                                log::error!("Failed to reconnect wifi: {:?}", e);
+                            }
+                            continue;
+                        }
+
+                        info!("Wifi still down, trying all networks");
+                        let r = BlockingWifi::wrap(&mut *wifi, sysloop.clone())
+                            .map_err(anyhow::Error::from)
+                            .and_then(|mut blocking| connect_best(&mut blocking, &networks, 1));
+                        // The events of these attempts are stale by now.
+                        loop {
+                            match rx.try_recv() {
+                                Ok(_) => {}
+                                Err(std::sync::mpsc::TryRecvError::Empty) => break,
+                                Err(std::sync::mpsc::TryRecvError::Disconnected) => return,
+                            }
+                        }
+                        match r {
+                            Ok(()) => {
+                                info!("Wifi reconnected");
+                                failures = 0;
+                                state_tx.send_replace(true);
+                            }
+                            Err(e) => {
+                                log::warn!("No network reachable: {:?}", e);
+                                retry = true;
+                            }
+                        }
+                    }
+                }
+            }
+        })?;
+
+    Ok((
+        Reconnect {
+            _wifi: wifi_sub,
+            _ip: ip_sub,
+        },
+        state_rx,
+    ))
+}
+
+/// RSSI of the access point in dBm, `None` while not connected.
+pub fn rssi() -> Option<i8> {
+    let mut info = esp_idf_svc::sys::wifi_ap_record_t::default();
+    esp!(unsafe { esp_idf_svc::sys::esp_wifi_sta_get_ap_info(&mut info) }).ok()?;
+    Some(info.rssi)
+}
+
+/// Host and port of a `ws://` or `wss://` URL.
+fn host_port(url: &str) -> Option<(&str, u16)> {
+    let (default_port, rest) = if let Some(rest) = url.strip_prefix("ws://") {
+        (80, rest)
+    } else if let Some(rest) = url.strip_prefix("wss://") {
+        (443, rest)
+    } else {
+        return None;
+    };
+    let authority = rest.split('/').next()?;
+    match authority.rsplit_once(':') {
+        Some((host, port)) => Some((host, port.parse().ok()?)),
+        None => Some((authority, default_port)),
+    }
+}
+
+/// Checks name resolution and TCP reachability of the server, one line per
+/// step, to show when the WebSocket connection fails.
+pub fn diagnose(url: &str) -> Vec<String> {
+    let Some((host, port)) = host_port(url) else {
+        return vec!["Invalid URL, expected ws:// or wss://".to_string()];
+    };
+    let mut report = vec![];
+
+    let addrs: Vec<SocketAddr> = match (host, port).to_socket_addrs() {
+        Ok(addrs) => addrs.collect(),
+        Err(e) => {
+            report.push(format!("DNS: {host} failed: {e}"));
+            return report;
+        }
+    };
+    let Some(addr) = addrs.first() else {
+        report.push(format!("DNS: {host} has no address"));
+        return report;
+    };
+    report.push(format!("DNS: {host} -> {}", addr.ip()));
+
+    match TcpStream::connect_timeout(addr, Duration::from_secs(5)) {
+        Ok(_) => report.push(format!("TCP: {addr} reachable")),
+        Err(e) => report.push(format!("TCP: {addr} failed: {e}")),
+    }
+    report
+}
+
 #[allow(unused)]
 pub fn http_get(url: &str) -> anyhow::Result<EspHttpConnection> {
     let configuration = esp_idf_svc::http::client::Configuration::default();
@@ -83,3 +536,45 @@ pub fn http_post(url: &str, data: &[u8]) -> anyhow::Result<EspHttpConnection> {
 
     Ok(conn)
 }
+
+#[test]
+fn test_rank() {
+    let network = |ssid: &str, priority| KnownNetwork {
+        ssid: ssid.to_string(),
+        priority,
+        ..Default::default()
+    };
+    let known = [
+        network("home", 1),
+        network("office", 1),
+        network("hidden", 5),
+        network("phone", 0),
+    ];
+    let visible = [
+        ("office", -50),
+        ("phone", -40),
+        ("home", -70),
+        ("office", -80),
+    ];
+    let ranked: Vec<&str> = rank(&known, &visible)
+        .iter()
+        .map(|n| n.ssid.as_str())
+        .collect();
+    assert_eq!(ranked, ["office", "home", "phone", "hidden"]);
+}
+
+#[test]
+fn test_host_port() {
+    assert_eq!(host_port("ws://example.com/ws/"), Some(("example.com", 80)));
+    assert_eq!(
+        host_port("wss://10.0.0.2:8443/ws/abc"),
+        Some(("10.0.0.2", 8443))
+    );
+    assert_eq!(host_port("http://example.com/"), None);
+}
+
+#[test]
+fn test_backoff() {
+    let delays: Vec<u64> = (0..8).map(|n| backoff(n).as_secs()).collect();
+    assert_eq!(delays, [1, 2, 4, 8, 16, 32, 32, 32]);
+}
@@ -0,0 +1,204 @@
+use esp_idf_svc::sys::{
+    esp, esp_ota_abort, esp_ota_begin, esp_ota_end, esp_ota_get_next_update_partition,
+    esp_ota_get_running_partition, esp_ota_get_state_partition, esp_ota_handle_t,
+    esp_ota_img_states_t, esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY,
+    esp_ota_mark_app_valid_cancel_rollback, esp_ota_set_boot_partition, esp_ota_write,
+    esp_partition_t, ESP_OK, OTA_WITH_SEQUENTIAL_WRITES,
+};
+use sha2::{Digest, Sha256};
+
+/// Ed25519 key that update images must be signed with, see `build.rs`.
+/// Without it updates are refused.
+const PUBLIC_KEY: Option<&str> = option_env!("ECHOKIT_OTA_PUBLIC_KEY");
+
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+pub fn parse_hex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != 2 * N {
+        return None;
+    }
+    let mut out = [0; N];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(s.get(2 * i..2 * i + 2)?, 16).ok()?;
+    }
+    Some(out)
+}
+
+/// Whether this build accepts firmware updates.
+pub fn enabled() -> bool {
+    PUBLIC_KEY.is_some()
+}
+
+/// Checks that `signature` is the signature of `digest` by `public_key`.
+fn verify(public_key: &[u8; 32], digest: &[u8; 32], signature: &[u8; 64]) -> anyhow::Result<()> {
+    let key = ed25519_dalek::VerifyingKey::from_bytes(public_key)
+        .map_err(|e| anyhow::anyhow!("Invalid public key: {}", e))?;
+    key.verify_strict(digest, &ed25519_dalek::Signature::from_bytes(signature))
+        .map_err(|_| anyhow::anyhow!("Bad signature"))
+}
+
+/// A firmware image being written to the inactive app slot. It is checked
+/// against its SHA-256 and signature before the slot is made bootable, and
+/// aborted when dropped before [`Update::finish`].
+pub struct Update {
+    handle: esp_ota_handle_t,
+    partition: *const esp_partition_t,
+    public_key: [u8; 32],
+    size: usize,
+    written: usize,
+    sha256: [u8; 32],
+    signature: [u8; 64],
+    hasher: Sha256,
+    ended: bool,
+}
+
+// The partition pointer refers to the partition table, which is never freed
+// or modified.
+unsafe impl Send for Update {}
+
+impl Update {
+    pub fn begin(
+        version: &str,
+        size: usize,
+        sha256: &[u8],
+        signature: &[u8],
+    ) -> anyhow::Result<Self> {
+        let public_key = PUBLIC_KEY
+            .and_then(parse_hex::<32>)
+            .ok_or_else(|| anyhow::anyhow!("Firmware updates are disabled in this build"))?;
+        let sha256: [u8; 32] = sha256
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid SHA-256 length {}", sha256.len()))?;
+        let signature: [u8; 64] = signature
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("Invalid signature length {}", signature.len()))?;
+        if version == VERSION {
+            anyhow::bail!("Firmware {} is already running", version);
+        }
+
+        let partition = unsafe { esp_ota_get_next_update_partition(std::ptr::null()) };
+        if partition.is_null() {
+            anyhow::bail!("No app slot for the update");
+        }
+        let slot_size = unsafe { (*partition).size } as usize;
+        if size == 0 || size > slot_size {
+            anyhow::bail!("Invalid image size {}, the slot has {}", size, slot_size);
+        }
+
+        log::info!(
+            "Updating firmware {} -> {}, {} bytes",
+            VERSION,
+            version,
+            size
+        );
+        // Erasing the whole slot up front blocks for seconds, sequential
+        // writes erase sector by sector instead.
+        let mut handle = 0;
+        esp!(unsafe {
+            esp_ota_begin(partition, OTA_WITH_SEQUENTIAL_WRITES as usize, &mut handle)
+        })?;
+        Ok(Self {
+            handle,
+            partition,
+            public_key,
+            size,
+            written: 0,
+            sha256,
+            signature,
+            hasher: Sha256::new(),
+            ended: false,
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        if self.written + data.len() > self.size {
+            anyhow::bail!("Image larger than {} bytes", self.size);
+        }
+        self.hasher.update(data);
+        esp!(unsafe { esp_ota_write(self.handle, data.as_ptr() as *const _, data.len()) })?;
+        self.written += data.len();
+        Ok(())
+    }
+
+    pub fn percent(&self) -> u8 {
+        (self.written * 100 / self.size) as u8
+    }
+
+    /// Verifies the image and boots it on the next restart.
+    pub fn finish(mut self) -> anyhow::Result<()> {
+        if self.written != self.size {
+            anyhow::bail!("Image truncated: {} of {} bytes", self.written, self.size);
+        }
+        let digest: [u8; 32] = std::mem::take(&mut self.hasher).finalize().into();
+        if digest != self.sha256 {
+            anyhow::bail!("SHA-256 mismatch");
+        }
+        verify(&self.public_key, &digest, &self.signature)?;
+
+        // esp_ota_end releases the handle even when the image is invalid.
+        self.ended = true;
+        esp!(unsafe { esp_ota_end(self.handle) })?;
+        esp!(unsafe { esp_ota_set_boot_partition(self.partition) })?;
+        log::info!("Firmware update verified");
+        Ok(())
+    }
+}
+
+impl Drop for Update {
+    fn drop(&mut self) {
+        if !self.ended {
+            log::warn!("Firmware update aborted at {} bytes", self.written);
+            unsafe { esp_ota_abort(self.handle) };
+        }
+    }
+}
+
+/// Describes an image uploaded without the server protocol, as hex strings
+/// of its SHA-256 and signature.
+#[derive(Debug, Clone, serde::Deserialize)]
+pub struct Manifest {
+    pub version: String,
+    pub sha256: String,
+    pub signature: String,
+}
+
+impl Manifest {
+    pub fn begin(&self, size: usize) -> anyhow::Result<Update> {
+        let sha256 = parse_hex::<32>(self.sha256.trim())
+            .ok_or_else(|| anyhow::anyhow!("Invalid SHA-256 {:?}", self.sha256))?;
+        let signature = parse_hex::<64>(self.signature.trim())
+            .ok_or_else(|| anyhow::anyhow!("Invalid signature {:?}", self.signature))?;
+        Update::begin(&self.version, size, &sha256, &signature)
+    }
+}
+
+/// Confirms a freshly updated firmware, otherwise the bootloader goes back
+/// to the previous one on the next restart.
+pub fn mark_valid() {
+    let mut state: esp_ota_img_states_t = 0;
+    let r = unsafe { esp_ota_get_state_partition(esp_ota_get_running_partition(), &mut state) };
+    if r == ESP_OK && state == esp_ota_img_states_t_ESP_OTA_IMG_PENDING_VERIFY {
+        match esp!(unsafe { esp_ota_mark_app_valid_cancel_rollback() }) {
+            Ok(()) => log::info!("Firmware {} marked valid", VERSION),
+            Err(e) => log::error!("Failed to mark firmware valid: {:?}", e),
+        }
+    }
+}
+
+#[test]
+fn test_verify() {
+    use ed25519_dalek::Signer;
+
+    assert_eq!(parse_hex::<2>("0aFf"), Some([0x0a, 0xff]));
+    assert_eq!(parse_hex::<2>("0aF"), None);
+    assert_eq!(parse_hex::<2>("0g00"), None);
+
+    let key = ed25519_dalek::SigningKey::from_bytes(&[7; 32]);
+    let digest: [u8; 32] = Sha256::digest(b"firmware").into();
+    let signature = key.sign(&digest).to_bytes();
+    let public_key = key.verifying_key().to_bytes();
+    assert!(verify(&public_key, &digest, &signature).is_ok());
+
+    let other: [u8; 32] = Sha256::digest(b"malware").into();
+    assert!(verify(&public_key, &other, &signature).is_err());
+}
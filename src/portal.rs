@@ -0,0 +1,456 @@
+use std::net::{Ipv4Addr, UdpSocket};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{mpsc, Arc, Mutex};
+use std::time::Duration;
+
+use esp_idf_svc::eventloop::EspSystemEventLoop;
+use esp_idf_svc::hal::modem::Modem;
+use esp_idf_svc::http::server::{Configuration, EspHttpConnection, EspHttpServer, Request};
+use esp_idf_svc::http::{Headers, Method};
+use esp_idf_svc::io::{Read, Write};
+use esp_idf_svc::wifi::{
+    AccessPointConfiguration, AuthMethod, BlockingWifi, ClientConfiguration, EspWifi,
+};
+
+use crate::network::SharedModem;
+use crate::SharedSetting;
+
+static PORTAL_HTML: &str = include_str!("../setup/portal.html");
+
+/// How often the DNS loop checks whether the portal should stop.
+const STOP_POLL: Duration = Duration::from_secs(1);
+
+/// The running portal, see [`stop`].
+struct Handle {
+    stop: Arc<AtomicBool>,
+    stopped: mpsc::Receiver<()>,
+}
+
+static PORTAL: Mutex<Option<Handle>> = Mutex::new(None);
+
+/// Starts a WPA2 softAP with a captive portal serving the same settings as
+/// the BLE service. The access point stays up until [`stop`] or the device
+/// restarts.
+///
+/// Returns the SSID, passphrase and IP address of the access point.
+pub fn spawn(
+    setting: SharedSetting,
+    modem: SharedModem,
+    sysloop: EspSystemEventLoop,
+) -> anyhow::Result<(String, String, Ipv4Addr)> {
+    let (tx, rx) = mpsc::channel();
+    let (stopped_tx, stopped) = mpsc::channel();
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop_ = stop.clone();
+    std::thread::Builder::new()
+        .stack_size(8 * 1024)
+        .spawn(move || {
+            let Ok(mut modem) = modem.try_lock() else {
+                let _ = tx.send(Err(anyhow::anyhow!("Wi-Fi busy")));
+                return;
+            };
+            match start(setting, &mut *modem, sysloop) {
+                Ok((wifi, server, ssid, pass, ip)) => {
+                    let _ = tx.send(Ok((ssid, pass, ip)));
+                    if let Err(e) = serve_dns(ip, &stop_) {
+                        log::error!("Captive portal DNS failed: {:?}", e);
+                    }
+                    // Keep the access point and the HTTP server alive.
+                    while !stop_.load(Ordering::Relaxed) {
+                        std::thread::sleep(STOP_POLL);
+                    }
+                    drop(server);
+                    drop(wifi);
+                }
+                Err(e) => {
+                    let _ = tx.send(Err(e));
+                }
+            }
+            drop(modem);
+            let _ = stopped_tx.send(());
+        })?;
+    let r = rx.recv()?;
+    if r.is_ok() {
+        *PORTAL.lock().unwrap() = Some(Handle { stop, stopped });
+    }
+    r
+}
+
+/// Stops a running portal and waits until it has released the modem, so
+/// BLE Wi-Fi tests and scans can use it.
+pub fn stop() {
+    let Some(handle) = PORTAL.lock().unwrap().take() else {
+        return;
+    };
+    handle.stop.store(true, Ordering::Relaxed);
+    let _ = handle.stopped.recv();
+    log::info!("Captive portal stopped");
+}
+
+/// A random WPA2 passphrase without look-alike characters, shown on the
+/// screen next to the SSID.
+fn passphrase() -> String {
+    const CHARS: &[u8] = b"23456789abcdefghjkmnpqrstuvwxyz";
+    (0..10)
+        .map(|_| {
+            let r = unsafe { esp_idf_svc::sys::esp_random() };
+            CHARS[r as usize % CHARS.len()] as char
+        })
+        .collect()
+}
+
+fn start<'d>(
+    setting: SharedSetting,
+    modem: &'d mut Modem,
+    sysloop: EspSystemEventLoop,
+) -> anyhow::Result<(
+    Box<EspWifi<'d>>,
+    EspHttpServer<'static>,
+    String,
+    String,
+    Ipv4Addr,
+)> {
+    let mut esp_wifi = EspWifi::new(modem, sysloop.clone(), None)?;
+    let mac = esp_wifi.ap_netif().get_mac()?;
+    let ssid = format!("EchoKit-{:02X}{:02X}", mac[4], mac[5]);
+    let pass = passphrase();
+
+    let mut wifi = BlockingWifi::wrap(&mut esp_wifi, sysloop)?;
+    wifi.set_configuration(&esp_idf_svc::wifi::Configuration::Mixed(
+        ClientConfiguration::default(),
+        AccessPointConfiguration {
+            ssid: ssid.as_str().try_into().unwrap(),
+            auth_method: AuthMethod::WPA2Personal,
+            password: pass.as_str().try_into().unwrap(),
+            channel: 1,
+            ..Default::default()
+        },
+    ))?;
+    wifi.start()?;
+
+    let scan = match wifi.scan() {
+        Ok(networks) => crate::wifi_scan::to_json(&networks, 4096),
+        Err(e) => {
+            log::warn!("Wifi scan failed: {:?}", e);
+            "[]".to_string()
+        }
+    };
+    let ip = wifi.wifi().ap_netif().get_ip_info()?.ip;
+    log::info!("Setup access point {} at http://{}/", ssid, ip);
+
+    let server = start_server(setting, scan, ip)?;
+    Ok((Box::new(esp_wifi), server, ssid, pass, ip))
+}
+
+fn read_body(
+    req: &mut Request<&mut EspHttpConnection<'_>>,
+    max_len: usize,
+) -> anyhow::Result<Vec<u8>> {
+    let len = req.content_len().unwrap_or(0) as usize;
+    if len > max_len {
+        anyhow::bail!("Request too large: {} bytes, max {}", len, max_len);
+    }
+    let mut body = vec![0; len];
+    let mut offset = 0;
+    while offset < len {
+        let n = req.read(&mut body[offset..])?;
+        if n == 0 {
+            break;
+        }
+        offset += n;
+    }
+    body.truncate(offset);
+    Ok(body)
+}
+
+#[derive(serde::Deserialize)]
+struct SettingsUpdate {
+    ssid: Option<String>,
+    pass: Option<String>,
+    auth: Option<crate::network::Auth>,
+    identity: Option<String>,
+    username: Option<String>,
+    server_url: Option<String>,
+    /// Of the network set by `ssid`, `null` switches back to DHCP. Saved
+    /// networks carry their own.
+    #[serde(default, deserialize_with = "present")]
+    ip_config: Option<Option<crate::network::IpConfig>>,
+    panels: Option<crate::ui::Panels>,
+    /// A built-in theme by name or a whole theme.
+    theme: Option<crate::ui::ThemeChoice>,
+}
+
+/// Tells an explicit `null` apart from a missing field.
+fn present<'de, D, T>(deserializer: D) -> Result<Option<Option<T>>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+    T: serde::Deserialize<'de>,
+{
+    serde::Deserialize::deserialize(deserializer).map(Some)
+}
+
+fn update_settings(setting: &SharedSetting, body: &[u8]) -> anyhow::Result<()> {
+    let update: SettingsUpdate = serde_json::from_slice(body)?;
+    let theme = update
+        .theme
+        .map(crate::ui::ThemeChoice::resolve)
+        .transpose()?;
+    setting.lock().unwrap().update(|s| {
+        if let Some(ssid) = update.ssid {
+            s.ssid = ssid;
+        }
+        if let Some(pass) = update.pass {
+            s.pass = pass;
+        }
+        if let Some(auth) = update.auth {
+            s.auth = auth;
+        }
+        if let Some(identity) = update.identity {
+            s.identity = identity;
+        }
+        if let Some(username) = update.username {
+            s.username = username;
+        }
+        if let Some(server_url) = update.server_url {
+            s.server_url = server_url;
+        }
+        if let Some(ip_config) = update.ip_config {
+            s.ip_config = ip_config;
+        }
+        if let Some((theme, panels)) = theme {
+            s.theme = theme;
+            if let Some(panels) = panels {
+                s.panels = panels;
+            }
+        }
+        // Given panels win over those of a built-in theme.
+        if let Some(panels) = update.panels {
+            s.panels = panels;
+        }
+    })
+}
+
+fn start_server(
+    setting: SharedSetting,
+    scan: String,
+    ip: Ipv4Addr,
+) -> anyhow::Result<EspHttpServer<'static>> {
+    let mut server = EspHttpServer::new(&Configuration {
+        uri_match_wildcard: true,
+        ..Default::default()
+    })?;
+
+    server.fn_handler("/", Method::Get, |req| -> anyhow::Result<()> {
+        let mut resp = req.into_response(200, None, &[("Content-Type", "text/html")])?;
+        resp.write_all(PORTAL_HTML.as_bytes())?;
+        Ok(())
+    })?;
+
+    let setting_ = setting.clone();
+    server.fn_handler(
+        "/api/settings",
+        Method::Get,
+        move |req| -> anyhow::Result<()> {
+            let body = {
+                let setting = setting_.lock().unwrap();
+                let networks: Vec<_> = setting
+                    .networks
+                    .iter()
+                    .map(|n| {
+                        serde_json::json!({
+                            "ssid": n.ssid,
+                            "priority": n.priority,
+                            "auth": n.auth,
+                            "ip": n.ip_config.map(|c| c.ip),
+                        })
+                    })
+                    .collect();
+                serde_json::json!({
+                    "ssid": setting.ssid,
+                    "server_url": setting.server_url,
+                    "networks": networks,
+                    "ip_config": setting.ip_config,
+                    "panels": setting.panels,
+                    "theme": setting.theme,
+                })
+                .to_string()
+            };
+            let mut resp = req.into_response(200, None, &[("Content-Type", "application/json")])?;
+            resp.write_all(body.as_bytes())?;
+            Ok(())
+        },
+    )?;
+
+    let setting_ = setting.clone();
+    server.fn_handler(
+        "/api/settings",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            let body = read_body(&mut req, 1024)?;
+            match update_settings(&setting_, &body) {
+                Ok(()) => req.into_ok_response()?,
+                Err(e) => req.into_response(400, Some(&e.to_string()), &[])?,
+            };
+            Ok(())
+        },
+    )?;
+
+    let setting_ = setting.clone();
+    server.fn_handler(
+        "/api/networks",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            let body = read_body(&mut req, 1024)?;
+            let r = setting_.lock().unwrap().update_networks(&body);
+            match r {
+                Ok(()) => req.into_ok_response()?,
+                Err(e) => req.into_response(400, Some(&e.to_string()), &[])?,
+            };
+            Ok(())
+        },
+    )?;
+
+    let setting_ = setting.clone();
+    server.fn_handler(
+        "/api/background",
+        Method::Post,
+        move |mut req| -> anyhow::Result<()> {
+            let len = req.content_len().unwrap_or(0) as usize;
+            if let Err(e) = crate::assets::check_put(crate::assets::BACKGROUND_GIF, len) {
+                req.into_response(400, Some(&e.to_string()), &[])?;
+                return Ok(());
+            }
+            let body = match read_body(&mut req, crate::assets::MAX_BACKGROUND_GIF_SIZE) {
+                Ok(body) if !body.is_empty() => body,
+                Ok(_) => {
+                    req.into_response(400, Some("Empty body"), &[])?;
+                    return Ok(());
+                }
+                Err(e) => {
+                    req.into_response(400, Some(&e.to_string()), &[])?;
+                    return Ok(());
+                }
+            };
+            log::info!("New background GIF received, size: {}", body.len());
+            setting_.lock().unwrap().background_gif = (body, true);
+            req.into_ok_response()?;
+            Ok(())
+        },
+    )?;
+
+    server.fn_handler("/api/assets", Method::Get, |req| -> anyhow::Result<()> {
+        let body = serde_json::to_string(&crate::assets::list()?)?;
+        let mut resp = req.into_response(200, None, &[("Content-Type", "application/json")])?;
+        resp.write_all(body.as_bytes())?;
+        Ok(())
+    })?;
+
+    server.fn_handler("/api/scan", Method::Get, move |req| -> anyhow::Result<()> {
+        let mut resp = req.into_response(200, None, &[("Content-Type", "application/json")])?;
+        resp.write_all(scan.as_bytes())?;
+        Ok(())
+    })?;
+
+    // Connectivity checks of phones and laptops land here, the redirect makes
+    // them open the portal.
+    let location = format!("http://{}/", ip);
+    server.fn_handler("/*", Method::Get, move |req| -> anyhow::Result<()> {
+        req.into_response(302, None, &[("Location", &location)])?;
+        Ok(())
+    })?;
+
+    log::info!("Captive portal HTTP server started");
+    Ok(server)
+}
+
+/// Answers every DNS query with the address of the access point until
+/// `stop` is set.
+fn serve_dns(ip: Ipv4Addr, stop: &AtomicBool) -> anyhow::Result<()> {
+    let socket = UdpSocket::bind("0.0.0.0:53")?;
+    socket.set_read_timeout(Some(STOP_POLL))?;
+    let mut buf = [0u8; 512];
+    while !stop.load(Ordering::Relaxed) {
+        let (len, peer) = match socket.recv_from(&mut buf) {
+            Ok(r) => r,
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                ) =>
+            {
+                continue
+            }
+            Err(e) => return Err(e.into()),
+        };
+        if let Some(resp) = dns_response(&buf[..len], ip) {
+            let _ = socket.send_to(&resp, peer);
+        }
+    }
+    Ok(())
+}
+
+fn dns_response(query: &[u8], ip: Ipv4Addr) -> Option<Vec<u8>> {
+    const HEADER_LEN: usize = 12;
+    const TYPE_A: u16 = 1;
+    const TYPE_ANY: u16 = 255;
+
+    if query.len() < HEADER_LEN || query[2] & 0x80 != 0 {
+        return None;
+    }
+    let qdcount = u16::from_be_bytes([query[4], query[5]]);
+    if qdcount == 0 {
+        return None;
+    }
+
+    // The first question: labels, terminating zero, type and class.
+    let mut end = HEADER_LEN;
+    loop {
+        let len = *query.get(end)? as usize;
+        if len & 0xC0 != 0 {
+            return None;
+        }
+        end += 1 + len;
+        if len == 0 {
+            break;
+        }
+    }
+    let qtype = u16::from_be_bytes([*query.get(end)?, *query.get(end + 1)?]);
+    end += 4;
+    if end > query.len() {
+        return None;
+    }
+
+    let answer = qtype == TYPE_A || qtype == TYPE_ANY;
+    let mut resp = Vec::with_capacity(end + 16);
+    resp.extend_from_slice(&query[0..2]);
+    // Response, recursion desired and available, no error.
+    resp.extend_from_slice(&[0x81, 0x80]);
+    resp.extend_from_slice(&1u16.to_be_bytes());
+    resp.extend_from_slice(&(answer as u16).to_be_bytes());
+    resp.extend_from_slice(&[0, 0, 0, 0]);
+    resp.extend_from_slice(&query[HEADER_LEN..end]);
+    if answer {
+        // Pointer to the name in the question, type A, class IN, TTL 60s.
+        resp.extend_from_slice(&[0xC0, 0x0C, 0, 1, 0, 1, 0, 0, 0, 60, 0, 4]);
+        resp.extend_from_slice(&ip.octets());
+    }
+    Some(resp)
+}
+
+#[test]
+fn test_dns_response() {
+    let mut query = vec![0x12, 0x34, 0x01, 0x00, 0, 1, 0, 0, 0, 0, 0, 0];
+    query.extend_from_slice(b"\x07example\x03com\x00");
+    query.extend_from_slice(&[0, 1, 0, 1]);
+
+    let resp = dns_response(&query, Ipv4Addr::new(192, 168, 71, 1)).unwrap();
+    assert_eq!(&resp[0..2], &[0x12, 0x34]);
+    assert_eq!(&resp[6..8], &[0, 1]);
+    assert_eq!(&resp[12..query.len()], &query[12..]);
+    assert_eq!(&resp[resp.len() - 4..], &[192, 168, 71, 1]);
+
+    query[query.len() - 3] = 28; // AAAA
+    let resp = dns_response(&query, Ipv4Addr::new(192, 168, 71, 1)).unwrap();
+    assert_eq!(&resp[6..8], &[0, 0]);
+    assert_eq!(resp.len(), query.len());
+}
@@ -4,22 +4,69 @@ use serde::{Deserialize, Serialize};
 pub enum ServerEvent {
     // set Hello
     HelloStart,
-    HelloChunk { data: Vec<u8> },
+    HelloChunk {
+        data: Vec<u8>,
+    },
     HelloEnd,
+    HelloReset,
 
     // set Background
     BGStart,
-    BGChunk { data: Vec<u8> },
+    BGChunk {
+        data: Vec<u8>,
+    },
     BGEnd,
 
-    ASR { text: String },
-    Action { action: String },
-    StartAudio { text: String },
-    AudioChunk { data: Vec<u8> },
+    ASR {
+        text: String,
+    },
+    Action {
+        action: String,
+    },
+    StartAudio {
+        text: String,
+        /// Sample rate of the following `AudioChunk`s, 16 kHz if absent.
+        #[serde(default)]
+        sample_rate: Option<u32>,
+    },
+    AudioChunk {
+        data: Vec<u8>,
+    },
     EndAudio,
     StartVideo,
     EndVideo,
     EndResponse,
+
+    // firmware update, see `ota.rs`
+    OtaStart {
+        version: String,
+        size: u32,
+        sha256: Vec<u8>,
+        /// Ed25519 signature of `sha256`.
+        signature: Vec<u8>,
+    },
+    OtaChunk {
+        data: Vec<u8>,
+    },
+    OtaEnd,
+
+    // streaming text
+    /// A hypothesis of the words heard so far, replaced by the next one and
+    /// finally by `ASR`.
+    PartialASR {
+        text: String,
+    },
+    /// More text of the sentence started by `StartAudio`, whose `text` may
+    /// then be empty.
+    AudioText {
+        text: String,
+    },
+
+    /// Replaces the theme of the UI and saves it in the settings, a built-in
+    /// theme also replaces the panels.
+    Theme {
+        theme: crate::ui::ThemeChoice,
+    },
 }
 
 #[test]
@@ -0,0 +1,193 @@
+/// Lowest and highest sample rates accepted from the server.
+pub const MIN_RATE: u32 = 8000;
+pub const MAX_RATE: u32 = 48000;
+
+pub fn is_supported_rate(rate: u32) -> bool {
+    (MIN_RATE..=MAX_RATE).contains(&rate)
+}
+
+/// Taps of the anti-aliasing filter, odd so that it delays by whole samples.
+const TAPS: usize = 63;
+/// Fraction of the output Nyquist frequency the filter passes.
+const PASSBAND: f32 = 0.8;
+
+/// Streaming linear-interpolation resampler for 16-bit little-endian mono PCM.
+///
+/// When downsampling, the input first goes through a low-pass FIR filter so
+/// that frequencies above the output Nyquist frequency do not alias. This
+/// delays the output by `TAPS / 2` input samples.
+///
+/// Chunks may be split at any byte, state is carried over between calls to
+/// [`Resampler::process`] so the output is continuous.
+pub struct Resampler {
+    from: u32,
+    to: u32,
+    /// Low-pass coefficients in Q15, empty unless downsampling.
+    taps: Vec<i32>,
+    /// The last `TAPS - 1` input samples, for the filter.
+    fir_history: Vec<i16>,
+    /// Position of the next output sample in units of `1 / to` input samples,
+    /// relative to `history`.
+    pos: u64,
+    history: i16,
+    pending: Option<u8>,
+}
+
+impl Resampler {
+    pub fn new(from: u32, to: u32) -> Self {
+        let taps = if from > to {
+            low_pass(PASSBAND * to as f32 / 2.0 / from as f32)
+        } else {
+            vec![]
+        };
+        Self {
+            from,
+            to,
+            fir_history: vec![0; taps.len().saturating_sub(1)],
+            taps,
+            pos: to as u64,
+            history: 0,
+            pending: None,
+        }
+    }
+
+    pub fn is_passthrough(&self) -> bool {
+        self.from == self.to
+    }
+
+    pub fn process(&mut self, input: &[u8]) -> Vec<u8> {
+        let mut bytes = input;
+        let mut samples = Vec::with_capacity(input.len() / 2 + 1);
+
+        if let Some(b) = self.pending.take() {
+            if let Some((&first, rest)) = bytes.split_first() {
+                samples.push(i16::from_le_bytes([b, first]));
+                bytes = rest;
+            } else {
+                self.pending = Some(b);
+                return vec![];
+            }
+        }
+        let mut chunks = bytes.chunks_exact(2);
+        samples.extend(chunks.by_ref().map(|c| i16::from_le_bytes([c[0], c[1]])));
+        if let [b] = chunks.remainder() {
+            self.pending = Some(*b);
+        }
+
+        if self.is_passthrough() {
+            return samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+        }
+        if !self.taps.is_empty() {
+            samples = self.filter(samples);
+        }
+
+        let n = samples.len() as u64;
+        let to = self.to as u64;
+        let sample_at = |i: u64| {
+            if i == 0 {
+                self.history
+            } else {
+                samples[i as usize - 1]
+            }
+        };
+
+        let mut out = Vec::with_capacity((n * self.to as u64 / self.from as u64) as usize * 2 + 2);
+        while self.pos / to < n {
+            let idx = self.pos / to;
+            let frac = (self.pos % to) as i64;
+            let a = sample_at(idx) as i64;
+            let b = sample_at(idx + 1) as i64;
+            let s = a + (b - a) * frac / to as i64;
+            out.extend_from_slice(&(s as i16).to_le_bytes());
+            self.pos += self.from as u64;
+        }
+
+        if let Some(&last) = samples.last() {
+            self.history = last;
+            self.pos -= n * to;
+        }
+        out
+    }
+
+    fn filter(&mut self, samples: Vec<i16>) -> Vec<i16> {
+        let mut input = std::mem::take(&mut self.fir_history);
+        input.extend_from_slice(&samples);
+        let filtered = input
+            .windows(self.taps.len())
+            .map(|w| {
+                let acc: i32 = w.iter().zip(&self.taps).map(|(&x, &h)| x as i32 * h).sum();
+                (acc >> 15).clamp(i16::MIN as i32, i16::MAX as i32) as i16
+            })
+            .collect();
+        input.drain(..input.len() + 1 - self.taps.len());
+        self.fir_history = input;
+        filtered
+    }
+}
+
+/// Hamming-windowed sinc with the cutoff `fc` in cycles per sample, in Q15
+/// and with unity gain at DC.
+fn low_pass(fc: f32) -> Vec<i32> {
+    use std::f32::consts::PI;
+
+    let m = (TAPS / 2) as f32;
+    let h: Vec<f32> = (0..TAPS)
+        .map(|n| {
+            let x = n as f32 - m;
+            let sinc = if x == 0.0 {
+                2.0 * fc
+            } else {
+                (2.0 * PI * fc * x).sin() / (PI * x)
+            };
+            let window = 0.54 - 0.46 * (2.0 * PI * n as f32 / (TAPS - 1) as f32).cos();
+            sinc * window
+        })
+        .collect();
+    let sum: f32 = h.iter().sum();
+    h.iter()
+        .map(|&c| (c / sum * 32768.0).round() as i32)
+        .collect()
+}
+
+#[test]
+fn test_resample() {
+    let input: Vec<u8> = (0..4800i16).flat_map(|s| s.to_le_bytes()).collect();
+
+    let mut r = Resampler::new(48000, 16000);
+    let mut out = vec![];
+    for chunk in input.chunks(333) {
+        out.extend(r.process(chunk));
+    }
+    assert_eq!(out.len(), 1600 * 2);
+    // The filter keeps a ramp, delayed by `TAPS / 2` input samples.
+    let s = i16::from_le_bytes([out[200], out[201]]);
+    assert!((s - (300 - TAPS as i16 / 2)).abs() <= 1, "{s}");
+
+    let mut r = Resampler::new(16000, 16000);
+    assert_eq!(r.process(&input[..101]), input[..100]);
+    assert_eq!(r.process(&input[101..200]), input[100..200]);
+}
+
+#[test]
+fn test_anti_aliasing() {
+    // Peak output of a tone after downsampling from 48 kHz to 16 kHz.
+    let peak = |freq: f32| {
+        let input: Vec<u8> = (0..4800)
+            .map(|i| {
+                let t = i as f32 / 48000.0;
+                (10000.0 * (2.0 * std::f32::consts::PI * freq * t).sin()) as i16
+            })
+            .flat_map(|s| s.to_le_bytes())
+            .collect();
+        let out = Resampler::new(48000, 16000).process(&input);
+        out.chunks_exact(2)
+            .skip(100)
+            .map(|c| i16::from_le_bytes([c[0], c[1]]).unsigned_abs())
+            .max()
+            .unwrap()
+    };
+    assert!(peak(1000.0) > 9000);
+    // Would alias to 6 kHz and 4 kHz.
+    assert!(peak(10000.0) < 300);
+    assert!(peak(12000.0) < 300);
+}
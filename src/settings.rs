@@ -0,0 +1,572 @@
+use esp_idf_svc::nvs::EspDefaultNvs;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::network::{Auth, IpConfig, KnownNetwork, MAX_KNOWN_NETWORKS};
+
+/// Schema version of the values in NVS. Bump it together with a step in
+/// [`migrate`] whenever stored values need to be rewritten.
+pub const VERSION: u8 = 1;
+const VERSION_KEY: &str = "version";
+
+const MAX_SSID_LEN: usize = 32;
+const MAX_PASS_LEN: usize = 64;
+const MAX_NAME_LEN: usize = 64;
+const MAX_URL_LEN: usize = 128;
+const MAX_JSON_LEN: usize = 1024;
+/// The saved networks in JSON, just below the 4000 bytes NVS takes in a
+/// string. Per-field limits keep [`MAX_KNOWN_NETWORKS`] networks below it.
+const MAX_NETWORKS_JSON_LEN: usize = 3999;
+
+/// Key shared by the fleet for signing settings exports, see `build.rs`.
+/// Without it export and import are refused.
+const EXPORT_KEY: Option<&str> = option_env!("ECHOKIT_SETTINGS_KEY");
+/// Length of the HMAC-SHA256 appended to an export.
+const TAG_LEN: usize = 32;
+
+/// Settings stored in the `setting` NVS namespace, one key per field.
+#[derive(Debug, Clone, Default, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub ssid: String,
+    pub pass: String,
+    pub auth: Auth,
+    /// EAP identity and username, used with [`Auth::Enterprise`].
+    pub identity: String,
+    pub username: String,
+    /// Static address of the network set by `ssid`, DHCP when `None`. Saved
+    /// networks carry their own.
+    pub ip_config: Option<IpConfig>,
+    /// Additional networks, see [`Settings::known_networks`].
+    pub networks: Vec<KnownNetwork>,
+    /// WebSocket URL ending with `/`, the device id is appended to it.
+    pub server_url: String,
+    pub diag: bool,
+    /// Colors and opacity of the panels behind the state and the text.
+    pub panels: crate::ui::Panels,
+    /// Fonts, colors and layout of the state and the text.
+    pub theme: crate::ui::Theme,
+}
+
+/// A field of [`Settings`], named after its NVS key.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Key {
+    Ssid,
+    Pass,
+    Auth,
+    Identity,
+    Username,
+    IpConfig,
+    Networks,
+    ServerUrl,
+    Diag,
+    Panels,
+    Theme,
+}
+
+impl Key {
+    pub const ALL: [Key; 11] = [
+        Key::Ssid,
+        Key::Pass,
+        Key::Auth,
+        Key::Identity,
+        Key::Username,
+        Key::IpConfig,
+        Key::Networks,
+        Key::ServerUrl,
+        Key::Diag,
+        Key::Panels,
+        Key::Theme,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Key::Ssid => "ssid",
+            Key::Pass => "pass",
+            Key::Auth => "auth",
+            Key::Identity => "identity",
+            Key::Username => "username",
+            Key::IpConfig => "ip_config",
+            Key::Networks => "networks",
+            Key::ServerUrl => "server_url",
+            Key::Diag => "diag",
+            Key::Panels => "panels",
+            Key::Theme => "theme",
+        }
+    }
+}
+
+impl std::fmt::Display for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+impl Settings {
+    /// The network set through the SSID and password fields.
+    pub fn primary_network(&self) -> KnownNetwork {
+        KnownNetwork {
+            ssid: self.ssid.clone(),
+            pass: self.pass.clone(),
+            priority: 0,
+            auth: self.auth,
+            identity: self.identity.clone(),
+            username: self.username.clone(),
+            ip_config: self.ip_config,
+        }
+    }
+
+    /// The saved networks plus the primary network, unless it is already in
+    /// the list.
+    pub fn known_networks(&self) -> Vec<KnownNetwork> {
+        let mut networks = self.networks.clone();
+        if !self.ssid.is_empty() && !networks.iter().any(|n| n.ssid == self.ssid) {
+            networks.push(self.primary_network());
+        }
+        networks
+    }
+
+    fn normalize(&mut self) {
+        if !self.server_url.is_empty() && !self.server_url.ends_with('/') {
+            self.server_url.push('/');
+        }
+    }
+
+    /// Checks one field. Fields are checked when they change, so a value
+    /// stored by older firmware does not block updates of the others.
+    pub fn validate(&self, key: Key) -> anyhow::Result<()> {
+        match key {
+            Key::Ssid => check_len("SSID", &self.ssid, MAX_SSID_LEN),
+            Key::Pass => check_len("password", &self.pass, MAX_PASS_LEN),
+            Key::Identity => check_len("identity", &self.identity, MAX_NAME_LEN),
+            Key::Username => check_len("username", &self.username, MAX_NAME_LEN),
+            Key::IpConfig => self.ip_config.as_ref().map_or(Ok(()), IpConfig::validate),
+            Key::Networks => {
+                if self.networks.len() > MAX_KNOWN_NETWORKS {
+                    anyhow::bail!("At most {} networks can be saved", MAX_KNOWN_NETWORKS);
+                }
+                for network in &self.networks {
+                    if network.ssid.is_empty() {
+                        anyhow::bail!("Empty SSID");
+                    }
+                    check_text("SSID", &network.ssid, MAX_SSID_LEN)?;
+                    check_text("password", &network.pass, MAX_PASS_LEN)?;
+                    check_text("identity", &network.identity, MAX_NAME_LEN)?;
+                    check_text("username", &network.username, MAX_NAME_LEN)?;
+                    if let Some(ip_config) = &network.ip_config {
+                        ip_config.validate()?;
+                    }
+                }
+                check_len(
+                    "networks",
+                    &serde_json::to_string(&self.networks)?,
+                    MAX_NETWORKS_JSON_LEN,
+                )
+            }
+            Key::ServerUrl => {
+                if self.server_url.is_empty() {
+                    return Ok(());
+                }
+                check_len("server URL", &self.server_url, MAX_URL_LEN)?;
+                let rest = self
+                    .server_url
+                    .strip_prefix("ws://")
+                    .or_else(|| self.server_url.strip_prefix("wss://"))
+                    .ok_or_else(|| {
+                        anyhow::anyhow!("The server URL must start with ws:// or wss://")
+                    })?;
+                if rest.starts_with('/') {
+                    anyhow::bail!("The server URL has no host");
+                }
+                Ok(())
+            }
+            Key::Panels => self.panels.validate(),
+            Key::Theme => self.theme.validate(),
+            Key::Auth | Key::Diag => Ok(()),
+        }
+    }
+
+    fn changed(&self, other: &Settings) -> Vec<Key> {
+        Key::ALL
+            .into_iter()
+            .filter(|&key| match key {
+                Key::Ssid => self.ssid != other.ssid,
+                Key::Pass => self.pass != other.pass,
+                Key::Auth => self.auth != other.auth,
+                Key::Identity => self.identity != other.identity,
+                Key::Username => self.username != other.username,
+                Key::IpConfig => self.ip_config != other.ip_config,
+                Key::Networks => self.networks != other.networks,
+                Key::ServerUrl => self.server_url != other.server_url,
+                Key::Diag => self.diag != other.diag,
+                Key::Panels => self.panels != other.panels,
+                Key::Theme => self.theme != other.theme,
+            })
+            .collect()
+    }
+}
+
+fn check_len(what: &str, value: &str, max: usize) -> anyhow::Result<()> {
+    if value.len() > max {
+        anyhow::bail!("The {} is longer than {} bytes", what, max);
+    }
+    Ok(())
+}
+
+/// Like [`check_len`], and rejects control characters, which JSON escapes
+/// to six bytes each.
+fn check_text(what: &str, value: &str, max: usize) -> anyhow::Result<()> {
+    check_len(what, value, max)?;
+    if value.chars().any(char::is_control) {
+        anyhow::bail!("The {} contains control characters", what);
+    }
+    Ok(())
+}
+
+fn get_str(nvs: &EspDefaultNvs, key: Key, max_len: usize) -> Option<String> {
+    let mut buf = vec![0; max_len + 1];
+    nvs.get_str(key.name(), &mut buf)
+        .map_err(|e| log::error!("Failed to get {}: {:?}", key, e))
+        .ok()
+        .flatten()
+        .map(str::to_string)
+}
+
+fn get_json<T: serde::de::DeserializeOwned>(
+    nvs: &EspDefaultNvs,
+    key: Key,
+    max_len: usize,
+) -> Option<T> {
+    get_str(nvs, key, max_len).and_then(|s| {
+        serde_json::from_str(&s)
+            .map_err(|e| log::error!("Failed to parse {}: {:?}", key, e))
+            .ok()
+    })
+}
+
+fn load(nvs: &EspDefaultNvs) -> Settings {
+    Settings {
+        ssid: get_str(nvs, Key::Ssid, MAX_SSID_LEN).unwrap_or_default(),
+        pass: get_str(nvs, Key::Pass, MAX_PASS_LEN).unwrap_or_default(),
+        auth: get_str(nvs, Key::Auth, 16)
+            .and_then(|s| Auth::parse(&s))
+            .unwrap_or_default(),
+        identity: get_str(nvs, Key::Identity, MAX_NAME_LEN).unwrap_or_default(),
+        username: get_str(nvs, Key::Username, MAX_NAME_LEN).unwrap_or_default(),
+        ip_config: get_json(nvs, Key::IpConfig, MAX_JSON_LEN),
+        networks: get_json(nvs, Key::Networks, MAX_NETWORKS_JSON_LEN).unwrap_or_default(),
+        server_url: get_str(nvs, Key::ServerUrl, MAX_URL_LEN).unwrap_or_default(),
+        diag: nvs
+            .get_u8(Key::Diag.name())
+            .map_err(|e| log::error!("Failed to get diag: {:?}", e))
+            .ok()
+            .flatten()
+            .unwrap_or(0)
+            != 0,
+        panels: get_json(nvs, Key::Panels, MAX_JSON_LEN).unwrap_or_default(),
+        theme: get_json(nvs, Key::Theme, MAX_JSON_LEN).unwrap_or_default(),
+    }
+}
+
+fn save(nvs: &mut EspDefaultNvs, settings: &Settings, key: Key) -> anyhow::Result<()> {
+    let name = key.name();
+    match key {
+        Key::Ssid => nvs.set_str(name, &settings.ssid)?,
+        Key::Pass => nvs.set_str(name, &settings.pass)?,
+        Key::Auth => nvs.set_str(name, settings.auth.as_str())?,
+        Key::Identity => nvs.set_str(name, &settings.identity)?,
+        Key::Username => nvs.set_str(name, &settings.username)?,
+        Key::IpConfig => match &settings.ip_config {
+            Some(ip_config) => nvs.set_str(name, &serde_json::to_string(ip_config)?)?,
+            None => {
+                nvs.remove(name)?;
+            }
+        },
+        Key::Networks => nvs.set_str(name, &serde_json::to_string(&settings.networks)?)?,
+        Key::ServerUrl => nvs.set_str(name, &settings.server_url)?,
+        Key::Diag => nvs.set_u8(name, settings.diag as u8)?,
+        Key::Panels => nvs.set_str(name, &serde_json::to_string(&settings.panels)?)?,
+        Key::Theme => nvs.set_str(name, &serde_json::to_string(&settings.theme)?)?,
+    }
+    Ok(())
+}
+
+/// Brings values written by older firmware up to [`VERSION`].
+fn migrate(nvs: &mut EspDefaultNvs, settings: &mut Settings, from: u8) -> anyhow::Result<()> {
+    log::info!("Migrating settings from version {} to {}", from, VERSION);
+    let old = settings.clone();
+    if from < 1 {
+        // Unversioned firmware stored the server URL as typed.
+        settings.normalize();
+        if settings.validate(Key::IpConfig).is_err() {
+            log::warn!("Dropping invalid IP config {:?}", settings.ip_config);
+            settings.ip_config = None;
+        }
+    }
+    for key in old.changed(settings) {
+        save(nvs, settings, key)?;
+    }
+    nvs.set_u8(VERSION_KEY, VERSION)?;
+    Ok(())
+}
+
+/// The whole settings set as exported, MessagePack with field names so that
+/// older and newer firmware can read it.
+#[derive(serde::Serialize, serde::Deserialize)]
+struct Export {
+    version: u8,
+    settings: Settings,
+}
+
+fn export_key() -> anyhow::Result<[u8; 32]> {
+    EXPORT_KEY
+        .and_then(crate::ota::parse_hex::<32>)
+        .ok_or_else(|| anyhow::anyhow!("Settings export is disabled in this build"))
+}
+
+fn mac(key: &[u8; 32], data: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(key).unwrap();
+    mac.update(data);
+    mac
+}
+
+/// `settings` as MessagePack followed by its HMAC-SHA256.
+fn seal(key: &[u8; 32], settings: &Settings) -> anyhow::Result<Vec<u8>> {
+    let mut blob = rmp_serde::to_vec_named(&Export {
+        version: VERSION,
+        settings: settings.clone(),
+    })?;
+    let tag = mac(key, &blob).finalize().into_bytes();
+    blob.extend_from_slice(&tag);
+    Ok(blob)
+}
+
+/// Checks and decodes a blob made by [`seal`].
+fn unseal(key: &[u8; 32], blob: &[u8]) -> anyhow::Result<Settings> {
+    if blob.len() < TAG_LEN {
+        anyhow::bail!("Settings export too short");
+    }
+    let (data, tag) = blob.split_at(blob.len() - TAG_LEN);
+    mac(key, data)
+        .verify_slice(tag)
+        .map_err(|_| anyhow::anyhow!("Bad settings signature"))?;
+    let export: Export = rmp_serde::from_slice(data)?;
+    if export.version > VERSION {
+        anyhow::bail!(
+            "Settings version {} is newer than {}",
+            export.version,
+            VERSION
+        );
+    }
+    Ok(export.settings)
+}
+
+/// The settings together with their NVS namespace. Reads go through
+/// `Deref`, writes through [`Store::update`], which validates, persists and
+/// notifies subscribers of the changed keys.
+pub struct Store {
+    nvs: EspDefaultNvs,
+    settings: Settings,
+    subscribers: Vec<tokio::sync::mpsc::UnboundedSender<Key>>,
+    /// Background GIF uploaded during setup, saved when leaving it.
+    pub background_gif: (Vec<u8>, bool), // (data, ended)
+}
+
+impl Store {
+    pub fn open(mut nvs: EspDefaultNvs) -> anyhow::Result<Self> {
+        let version = nvs.get_u8(VERSION_KEY)?.unwrap_or(0);
+        let mut settings = load(&nvs);
+        if version < VERSION {
+            // Retried on the next boot.
+            if let Err(e) = migrate(&mut nvs, &mut settings, version) {
+                log::error!("Failed to migrate settings: {:?}", e);
+            }
+        } else if version > VERSION {
+            log::warn!(
+                "Settings version {} is newer than {}, keeping them as they are",
+                version,
+                VERSION
+            );
+        }
+        Ok(Self {
+            nvs,
+            settings,
+            subscribers: vec![],
+            background_gif: (vec![], false),
+        })
+    }
+
+    pub fn update(&mut self, f: impl FnOnce(&mut Settings)) -> anyhow::Result<()> {
+        let mut settings = self.settings.clone();
+        f(&mut settings);
+        settings.normalize();
+        let changed = self.settings.changed(&settings);
+        for &key in &changed {
+            settings.validate(key)?;
+        }
+        for &key in &changed {
+            save(&mut self.nvs, &settings, key)?;
+            log::info!("Setting {} updated", key);
+        }
+        self.settings = settings;
+        self.subscribers
+            .retain(|tx| changed.iter().all(|&key| tx.send(key).is_ok()));
+        Ok(())
+    }
+
+    /// Receives the key of every field changed from now on.
+    pub fn subscribe(&mut self) -> tokio::sync::mpsc::UnboundedReceiver<Key> {
+        let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+        self.subscribers.push(tx);
+        rx
+    }
+
+    /// The whole settings set, signed for [`Store::import`] on another
+    /// device. It includes the Wi-Fi passwords.
+    pub fn export(&self) -> anyhow::Result<Vec<u8>> {
+        seal(&export_key()?, &self.settings)
+    }
+
+    /// Replaces all settings with an export signed by the same fleet key.
+    pub fn import(&mut self, blob: &[u8]) -> anyhow::Result<()> {
+        let settings = unseal(&export_key()?, blob)?;
+        self.update(|s| *s = settings)?;
+        log::info!("Settings imported");
+        Ok(())
+    }
+
+    /// Erases all settings and the storage partition. The device should
+    /// restart afterwards.
+    pub fn factory_reset(&mut self) -> anyhow::Result<()> {
+        log::warn!("Factory reset");
+        for key in Key::ALL {
+            self.nvs.remove(key.name())?;
+        }
+        self.nvs.set_u8(VERSION_KEY, VERSION)?;
+        self.settings = Settings::default();
+        self.background_gif = (vec![], false);
+        crate::storage::clear()?;
+        Ok(())
+    }
+
+    /// Adds, replaces or, with `"delete": true`, removes a saved network
+    /// given as JSON.
+    pub fn update_networks(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        #[derive(serde::Deserialize)]
+        struct NetworkUpdate {
+            #[serde(flatten)]
+            network: KnownNetwork,
+            #[serde(default)]
+            delete: bool,
+        }
+
+        let update: NetworkUpdate = serde_json::from_slice(data)?;
+        self.update(|s| {
+            s.networks.retain(|n| n.ssid != update.network.ssid);
+            if !update.delete {
+                s.networks.push(update.network);
+            }
+        })
+    }
+
+    /// Sets the static IP configuration from JSON, or clears it with an
+    /// empty value or `null`.
+    pub fn update_ip_config(&mut self, data: &[u8]) -> anyhow::Result<()> {
+        let ip_config = if data.is_empty() {
+            None
+        } else {
+            serde_json::from_slice(data)?
+        };
+        self.update(|s| s.ip_config = ip_config)
+    }
+
+    /// Sets the theme, and the panels with a built-in one.
+    pub fn update_theme(&mut self, choice: crate::ui::ThemeChoice) -> anyhow::Result<()> {
+        let (theme, panels) = choice.resolve()?;
+        self.update(|s| {
+            s.theme = theme;
+            if let Some(panels) = panels {
+                s.panels = panels;
+            }
+        })
+    }
+}
+
+impl std::ops::Deref for Store {
+    type Target = Settings;
+
+    fn deref(&self) -> &Settings {
+        &self.settings
+    }
+}
+
+#[test]
+fn test_validate() {
+    let mut settings = Settings::default();
+    assert!(settings.validate(Key::ServerUrl).is_ok());
+
+    settings.server_url = "wss://echokit.example/ws".to_string();
+    settings.normalize();
+    assert_eq!(settings.server_url, "wss://echokit.example/ws/");
+    assert!(settings.validate(Key::ServerUrl).is_ok());
+
+    settings.server_url = "http://echokit.example/ws/".to_string();
+    assert!(settings.validate(Key::ServerUrl).is_err());
+    settings.server_url = "ws:///ws/".to_string();
+    assert!(settings.validate(Key::ServerUrl).is_err());
+
+    settings.networks = (0..=MAX_KNOWN_NETWORKS)
+        .map(|i| KnownNetwork {
+            ssid: format!("network{}", i),
+            ..Default::default()
+        })
+        .collect();
+    assert!(settings.validate(Key::Networks).is_err());
+    settings.networks.pop();
+    assert!(settings.validate(Key::Networks).is_ok());
+
+    // Quotes take two bytes in JSON, the longest fields still fit.
+    settings.networks = (0..MAX_KNOWN_NETWORKS)
+        .map(|_| KnownNetwork {
+            ssid: "\"".repeat(MAX_SSID_LEN),
+            pass: "\"".repeat(MAX_PASS_LEN),
+            priority: u8::MAX,
+            auth: Auth::Enterprise,
+            identity: "\"".repeat(MAX_NAME_LEN),
+            username: "\"".repeat(MAX_NAME_LEN),
+            ip_config: Some(IpConfig {
+                ip: std::net::Ipv4Addr::new(255, 255, 255, 1),
+                prefix: 30,
+                gateway: std::net::Ipv4Addr::new(255, 255, 255, 2),
+                dns: Some(std::net::Ipv4Addr::BROADCAST),
+                secondary_dns: Some(std::net::Ipv4Addr::BROADCAST),
+            }),
+        })
+        .collect();
+    assert!(settings.validate(Key::Networks).is_ok());
+    settings.networks[0].username = "\u{1}".to_string();
+    assert!(settings.validate(Key::Networks).is_err());
+
+    let mut other = settings.clone();
+    other.diag = true;
+    other.ssid = "home".to_string();
+    assert_eq!(settings.changed(&other), [Key::Ssid, Key::Diag]);
+}
+
+#[test]
+fn test_export() {
+    let key = [7; 32];
+    let settings = Settings {
+        ssid: "home".to_string(),
+        server_url: "wss://echokit.example/ws/".to_string(),
+        diag: true,
+        ..Default::default()
+    };
+    let mut blob = seal(&key, &settings).unwrap();
+    assert_eq!(unseal(&key, &blob).unwrap(), settings);
+    assert!(unseal(&[8; 32], &blob).is_err());
+
+    blob[0] ^= 1;
+    assert!(unseal(&key, &blob).is_err());
+    assert!(unseal(&key, &blob[..TAG_LEN - 1]).is_err());
+}
@@ -0,0 +1,153 @@
+use esp_idf_svc::sys::EspError;
+
+pub const BASE_PATH: &str = "/storage";
+
+/// Mounts the `storage` FAT partition at [`BASE_PATH`], formatting it on first use.
+pub fn mount() -> Result<(), EspError> {
+    use esp_idf_svc::sys::*;
+
+    let mount_config = esp_vfs_fat_mount_config_t {
+        format_if_mount_failed: true,
+        max_files: 4,
+        allocation_unit_size: CONFIG_WL_SECTOR_SIZE as _,
+        ..Default::default()
+    };
+    let mut wl_handle: wl_handle_t = -1;
+    esp!(unsafe {
+        esp_vfs_fat_spiflash_mount_rw_wl(
+            "/storage\0".as_ptr() as *const _,
+            "storage\0".as_ptr() as *const _,
+            &mount_config,
+            &mut wl_handle,
+        )
+    })?;
+    log::info!("Storage mounted at {}", BASE_PATH);
+    Ok(())
+}
+
+pub fn path(name: &str) -> String {
+    format!("{}/{}", BASE_PATH, name)
+}
+
+/// Free bytes on the partition.
+pub fn free_space() -> Result<u64, EspError> {
+    use esp_idf_svc::sys::*;
+
+    let (mut total, mut free) = (0, 0);
+    esp!(unsafe { esp_vfs_fat_info("/storage\0".as_ptr() as *const _, &mut total, &mut free) })?;
+    Ok(free)
+}
+
+/// Space a file of `len` bytes takes, whole clusters of one sector.
+pub fn allocated(len: usize) -> u64 {
+    let cluster = esp_idf_svc::sys::CONFIG_WL_SECTOR_SIZE as u64;
+    (len as u64).div_ceil(cluster) * cluster
+}
+
+const CHECKED_MAGIC: &[u8; 4] = b"EKF1";
+/// Magic, length and CRC32 in front of the data of a checked file.
+pub const CHECKED_HEADER_LEN: usize = 12;
+
+/// Writes `data` to `name` prefixed with a magic, its length and a CRC32,
+/// so that [`read_checked`] can reject truncated or corrupted files.
+/// Returns the CRC32.
+pub fn write_checked(name: &str, data: &[u8]) -> std::io::Result<u32> {
+    use std::io::Write;
+
+    let tmp = path(&format!("{}.tmp", name));
+    let crc = crc32(data);
+    {
+        let mut file = std::fs::File::create(&tmp)?;
+        file.write_all(CHECKED_MAGIC)?;
+        file.write_all(&(data.len() as u32).to_le_bytes())?;
+        file.write_all(&crc.to_le_bytes())?;
+        file.write_all(data)?;
+    }
+    let _ = std::fs::remove_file(path(name));
+    std::fs::rename(tmp, path(name))?;
+    Ok(crc)
+}
+
+/// Length and CRC32 recorded by [`write_checked`], without reading the data.
+pub fn checked_header(name: &str) -> Option<(usize, u32)> {
+    use std::io::Read;
+
+    let mut file = std::fs::File::open(path(name)).ok()?;
+    let mut header = [0u8; 12];
+    file.read_exact(&mut header).ok()?;
+    if &header[0..4] != CHECKED_MAGIC {
+        return None;
+    }
+    let len = u32::from_le_bytes(header[4..8].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(header[8..12].try_into().unwrap());
+    if file.metadata().ok()?.len() != 12 + len as u64 {
+        return None;
+    }
+    Some((len, crc))
+}
+
+/// Reads a file written by [`write_checked`], returns `None` if it is missing,
+/// larger than `max_len` or fails the checksum.
+pub fn read_checked(name: &str, max_len: usize) -> Option<Vec<u8>> {
+    let buf = std::fs::read(path(name)).ok()?;
+    if buf.len() < 12 || &buf[0..4] != CHECKED_MAGIC {
+        log::warn!("{}: bad header", name);
+        return None;
+    }
+    let len = u32::from_le_bytes(buf[4..8].try_into().unwrap()) as usize;
+    let crc = u32::from_le_bytes(buf[8..12].try_into().unwrap());
+    let data = &buf[12..];
+    if len > max_len || data.len() != len {
+        log::warn!("{}: bad length {} (file {})", name, len, data.len());
+        return None;
+    }
+    if crc32(data) != crc {
+        log::warn!("{}: checksum mismatch", name);
+        return None;
+    }
+    Some(data.to_vec())
+}
+
+pub fn remove(name: &str) -> std::io::Result<()> {
+    match std::fs::remove_file(path(name)) {
+        Err(e) if e.kind() != std::io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+/// Removes every file and directory on the partition.
+pub fn clear() -> std::io::Result<()> {
+    for entry in std::fs::read_dir(BASE_PATH)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            std::fs::remove_dir_all(entry.path())?;
+        } else {
+            std::fs::remove_file(entry.path())?;
+        }
+    }
+    Ok(())
+}
+
+/// CRC-32 (IEEE 802.3), the same as zlib's `crc32`.
+pub fn crc32(data: &[u8]) -> u32 {
+    crc32_update(0, data)
+}
+
+/// Continues a CRC-32 over more data, `crc32_update(crc32(a), b) == crc32(a ++ b)`.
+pub fn crc32_update(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &b in data {
+        crc ^= b as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (!(crc & 1)).wrapping_add(1));
+        }
+    }
+    !crc
+}
+
+#[test]
+fn test_crc32() {
+    assert_eq!(crc32(b""), 0);
+    assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    assert_eq!(crc32_update(crc32(b"1234"), b"56789"), 0xCBF4_3926);
+}
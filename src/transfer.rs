@@ -0,0 +1,329 @@
+//! Framed transfer of large payloads over a single BLE characteristic.
+//!
+//! The client writes frames, the device answers every frame with a 5 byte
+//! notification `[status, u32 LE]`.
+//!
+//! | frame                                              | answer                  |
+//! |----------------------------------------------------|-------------------------|
+//! | `START kind:u8 total_size:u32 crc32:u32`           | `OK next_seq`           |
+//! | `CHUNK seq:u32 data`                               | `OK seq + 1` or `DONE`  |
+//! | `ABORT`                                            | `ABORTED`               |
+//!
+//! Chunks are numbered from 0 and must arrive in order. A repeated chunk is
+//! acknowledged again without being written, so the client can simply resend
+//! when an ack is lost. Sending `START` again with the same kind, size and
+//! CRC after a disconnect resumes at the sequence number in the answer.
+//! After `DONE`, the final chunk or that `START` is answered with `DONE`
+//! again, in case the first `DONE` was lost.
+
+/// Frame types written by the client.
+const START: u8 = 0x01;
+const CHUNK: u8 = 0x02;
+const ABORT: u8 = 0x03;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Kind {
+    Gif = 1,
+    Hello = 2,
+    Cert = 3,
+    Firmware = 4,
+    /// A settings export, see [`crate::settings::Store::import`].
+    Settings = 5,
+}
+
+impl TryFrom<u8> for Kind {
+    type Error = u8;
+
+    fn try_from(value: u8) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Kind::Gif),
+            2 => Ok(Kind::Hello),
+            3 => Ok(Kind::Cert),
+            4 => Ok(Kind::Firmware),
+            5 => Ok(Kind::Settings),
+            v => Err(v),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Status {
+    /// Value is the next expected sequence number.
+    Ok = 0,
+    /// All data received and the CRC matched, value is the total size.
+    Done = 1,
+    /// Value is the expected sequence number.
+    OutOfOrder = 2,
+    BadCrc = 3,
+    /// More data than announced in `START`.
+    TooLarge = 4,
+    /// The payload was refused by the device, e.g. an unsupported kind or
+    /// a file that failed validation.
+    Rejected = 5,
+    NoTransfer = 6,
+    Malformed = 7,
+    Aborted = 8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ack {
+    pub status: Status,
+    pub value: u32,
+}
+
+impl Ack {
+    fn new(status: Status, value: u32) -> Self {
+        Self { status, value }
+    }
+
+    pub fn to_bytes(self) -> [u8; 5] {
+        let v = self.value.to_le_bytes();
+        [self.status as u8, v[0], v[1], v[2], v[3]]
+    }
+}
+
+/// Destination of a transfer.
+pub trait Sink {
+    /// Called for a new transfer, an error answers `REJECTED`.
+    fn begin(&mut self, kind: Kind, total_size: u32) -> anyhow::Result<()>;
+    fn write(&mut self, data: &[u8]) -> anyhow::Result<()>;
+    /// Called once all data arrived and the CRC matched.
+    fn finish(&mut self, kind: Kind) -> anyhow::Result<()>;
+    fn abort(&mut self, kind: Kind);
+}
+
+struct State {
+    kind: Kind,
+    total_size: u32,
+    crc32: u32,
+    received: u32,
+    next_seq: u32,
+    running_crc: u32,
+}
+
+/// The last transfer that ended with `DONE`.
+struct Completed {
+    kind: Kind,
+    total_size: u32,
+    crc32: u32,
+    next_seq: u32,
+}
+
+#[derive(Default)]
+pub struct Receiver {
+    state: Option<State>,
+    last: Option<Completed>,
+}
+
+impl Receiver {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn handle<S: Sink>(&mut self, frame: &[u8], sink: &mut S) -> Ack {
+        match frame.split_first() {
+            Some((&START, args)) => self.start(args, sink),
+            Some((&CHUNK, args)) if args.len() >= 4 => {
+                let seq = u32::from_le_bytes(args[..4].try_into().unwrap());
+                self.chunk(seq, &args[4..], sink)
+            }
+            Some((&ABORT, _)) => {
+                self.last = None;
+                if let Some(state) = self.state.take() {
+                    log::info!("Transfer of {:?} aborted", state.kind);
+                    sink.abort(state.kind);
+                }
+                Ack::new(Status::Aborted, 0)
+            }
+            _ => Ack::new(Status::Malformed, 0),
+        }
+    }
+
+    fn start<S: Sink>(&mut self, args: &[u8], sink: &mut S) -> Ack {
+        if args.len() != 9 {
+            return Ack::new(Status::Malformed, 0);
+        }
+        let Ok(kind) = Kind::try_from(args[0]) else {
+            return Ack::new(Status::Rejected, 0);
+        };
+        let total_size = u32::from_le_bytes(args[1..5].try_into().unwrap());
+        let crc32 = u32::from_le_bytes(args[5..9].try_into().unwrap());
+        if total_size == 0 {
+            return Ack::new(Status::Malformed, 0);
+        }
+        if let Some(last) = &self.last {
+            if last.kind == kind && last.total_size == total_size && last.crc32 == crc32 {
+                log::info!("Transfer of {:?} already done", kind);
+                return Ack::new(Status::Done, total_size);
+            }
+        }
+        self.last = None;
+
+        if let Some(state) = &self.state {
+            if state.kind == kind && state.total_size == total_size && state.crc32 == crc32 {
+                log::info!(
+                    "Resuming transfer of {:?} at chunk {} ({}/{} bytes)",
+                    kind,
+                    state.next_seq,
+                    state.received,
+                    total_size
+                );
+                return Ack::new(Status::Ok, state.next_seq);
+            }
+            sink.abort(state.kind);
+            self.state = None;
+        }
+
+        if let Err(e) = sink.begin(kind, total_size) {
+            log::error!("Transfer of {:?} rejected: {:?}", kind, e);
+            return Ack::new(Status::Rejected, 0);
+        }
+        log::info!("Transfer of {:?} started, {} bytes", kind, total_size);
+        self.state = Some(State {
+            kind,
+            total_size,
+            crc32,
+            received: 0,
+            next_seq: 0,
+            running_crc: 0,
+        });
+        Ack::new(Status::Ok, 0)
+    }
+
+    fn chunk<S: Sink>(&mut self, seq: u32, data: &[u8], sink: &mut S) -> Ack {
+        let Some(state) = &mut self.state else {
+            return match &self.last {
+                Some(last) if seq.wrapping_add(1) == last.next_seq => {
+                    Ack::new(Status::Done, last.total_size)
+                }
+                _ => Ack::new(Status::NoTransfer, 0),
+            };
+        };
+        if seq.wrapping_add(1) == state.next_seq {
+            return Ack::new(Status::Ok, state.next_seq);
+        }
+        if seq != state.next_seq {
+            return Ack::new(Status::OutOfOrder, state.next_seq);
+        }
+
+        let kind = state.kind;
+        if state.received as u64 + data.len() as u64 > state.total_size as u64 {
+            self.state = None;
+            sink.abort(kind);
+            return Ack::new(Status::TooLarge, 0);
+        }
+        if let Err(e) = sink.write(data) {
+            log::error!("Transfer of {:?} failed: {:?}", kind, e);
+            self.state = None;
+            sink.abort(kind);
+            return Ack::new(Status::Rejected, 0);
+        }
+        state.received += data.len() as u32;
+        state.running_crc = crate::storage::crc32_update(state.running_crc, data);
+        state.next_seq += 1;
+
+        if state.received < state.total_size {
+            return Ack::new(Status::Ok, state.next_seq);
+        }
+
+        let state = self.state.take().unwrap();
+        if state.running_crc != state.crc32 {
+            log::error!(
+                "Transfer of {:?} CRC mismatch: {:08x} != {:08x}",
+                kind,
+                state.running_crc,
+                state.crc32
+            );
+            sink.abort(kind);
+            return Ack::new(Status::BadCrc, 0);
+        }
+        if let Err(e) = sink.finish(kind) {
+            log::error!("Transfer of {:?} rejected: {:?}", kind, e);
+            return Ack::new(Status::Rejected, 0);
+        }
+        log::info!("Transfer of {:?} done, {} bytes", kind, state.total_size);
+        self.last = Some(Completed {
+            kind,
+            total_size: state.total_size,
+            crc32: state.crc32,
+            next_seq: state.next_seq,
+        });
+        Ack::new(Status::Done, state.total_size)
+    }
+}
+
+#[test]
+fn test_transfer() {
+    #[derive(Default)]
+    struct VecSink {
+        data: Vec<u8>,
+        done: bool,
+    }
+
+    impl Sink for VecSink {
+        fn begin(&mut self, _kind: Kind, _total_size: u32) -> anyhow::Result<()> {
+            self.data.clear();
+            Ok(())
+        }
+        fn write(&mut self, data: &[u8]) -> anyhow::Result<()> {
+            self.data.extend_from_slice(data);
+            Ok(())
+        }
+        fn finish(&mut self, _kind: Kind) -> anyhow::Result<()> {
+            self.done = true;
+            Ok(())
+        }
+        fn abort(&mut self, _kind: Kind) {}
+    }
+
+    let payload: Vec<u8> = (0..=255u8).cycle().take(1000).collect();
+    let mut start = vec![START, Kind::Gif as u8];
+    start.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    start.extend_from_slice(&crate::storage::crc32(&payload).to_le_bytes());
+    let chunk = |seq: u32| {
+        let mut frame = vec![CHUNK];
+        frame.extend_from_slice(&seq.to_le_bytes());
+        frame.extend(payload.chunks(300).nth(seq as usize).unwrap());
+        frame
+    };
+
+    let mut rx = Receiver::new();
+    let mut sink = VecSink::default();
+    assert_eq!(rx.handle(&chunk(0), &mut sink).status, Status::NoTransfer);
+    assert_eq!(rx.handle(&start, &mut sink), Ack::new(Status::Ok, 0));
+    assert_eq!(rx.handle(&chunk(0), &mut sink), Ack::new(Status::Ok, 1));
+    assert_eq!(rx.handle(&chunk(0), &mut sink), Ack::new(Status::Ok, 1));
+    assert_eq!(
+        rx.handle(&chunk(2), &mut sink),
+        Ack::new(Status::OutOfOrder, 1)
+    );
+    assert_eq!(rx.handle(&chunk(1), &mut sink), Ack::new(Status::Ok, 2));
+    // Reconnect and resume.
+    assert_eq!(rx.handle(&start, &mut sink), Ack::new(Status::Ok, 2));
+    assert_eq!(rx.handle(&chunk(2), &mut sink), Ack::new(Status::Ok, 3));
+    assert_eq!(
+        rx.handle(&chunk(3), &mut sink),
+        Ack::new(Status::Done, 1000)
+    );
+    assert!(sink.done);
+    assert_eq!(sink.data, payload);
+    // The DONE ack was lost, the final chunk or START is resent.
+    sink.done = false;
+    assert_eq!(
+        rx.handle(&chunk(3), &mut sink),
+        Ack::new(Status::Done, 1000)
+    );
+    assert_eq!(rx.handle(&start, &mut sink), Ack::new(Status::Done, 1000));
+    assert!(!sink.done);
+    assert_eq!(rx.handle(&chunk(2), &mut sink).status, Status::NoTransfer);
+
+    let mut bad = start.clone();
+    bad[6] ^= 1;
+    assert_eq!(rx.handle(&bad, &mut sink), Ack::new(Status::Ok, 0));
+    for seq in 0..3 {
+        rx.handle(&chunk(seq), &mut sink);
+    }
+    assert_eq!(rx.handle(&chunk(3), &mut sink).status, Status::BadCrc);
+}
@@ -3,141 +3,44 @@ use embedded_graphics::{
     image::GetPixel,
     pixelcolor::{
         raw::{LittleEndian, RawU16},
-        Rgb565,
+        Rgb565, Rgb888,
     },
     prelude::*,
-    primitives::{PrimitiveStyleBuilder, Rectangle},
+    primitives::{Circle, Line, PrimitiveStyle, PrimitiveStyleBuilder, Rectangle, Triangle},
     text::{
         renderer::{CharacterStyle, TextRenderer},
         Alignment, Text,
     },
 };
 use embedded_text::TextBox;
-use esp_idf_svc::sys::EspError;
 use u8g2_fonts::U8g2TextStyle;
 
-pub type ColorFormat = Rgb565;
+use std::borrow::Cow;
+use std::time::{Duration, Instant};
 
-#[cfg(feature = "boards")]
-const DISPLAY_WIDTH: usize = 240;
-#[cfg(feature = "boards")]
-const DISPLAY_HEIGHT: usize = 240;
-
-#[cfg(feature = "box")]
-const DISPLAY_WIDTH: usize = 320;
-#[cfg(feature = "box")]
-const DISPLAY_HEIGHT: usize = 240;
-
-fn init_spi() -> Result<(), EspError> {
-    use esp_idf_svc::sys::*;
-    const GPIO_NUM_NC: i32 = -1;
-    const DISPLAY_MOSI_PIN: i32 = 47;
-    const DISPLAY_CLK_PIN: i32 = 21;
-    let mut buscfg = spi_bus_config_t::default();
-    buscfg.__bindgen_anon_1.mosi_io_num = DISPLAY_MOSI_PIN;
-    buscfg.__bindgen_anon_2.miso_io_num = GPIO_NUM_NC;
-    buscfg.sclk_io_num = DISPLAY_CLK_PIN;
-    buscfg.__bindgen_anon_3.quadwp_io_num = GPIO_NUM_NC;
-    buscfg.__bindgen_anon_4.quadhd_io_num = GPIO_NUM_NC;
-    buscfg.max_transfer_sz = (DISPLAY_WIDTH * DISPLAY_HEIGHT * std::mem::size_of::<u16>()) as i32;
-    esp!(unsafe {
-        spi_bus_initialize(
-            spi_host_device_t_SPI3_HOST,
-            &buscfg,
-            spi_common_dma_t_SPI_DMA_CH_AUTO,
-        )
-    })
-}
-
-static mut ESP_LCD_PANEL_HANDLE: esp_idf_svc::sys::esp_lcd_panel_handle_t = std::ptr::null_mut();
-
-#[cfg(feature = "boards")]
-fn init_lcd() -> Result<(), EspError> {
-    use esp_idf_svc::sys::*;
-    const DISPLAY_CS_PIN: i32 = 41;
-    const DISPLAY_DC_PIN: i32 = 40;
-    ::log::info!("Install panel IO");
-    let mut panel_io: esp_lcd_panel_io_handle_t = std::ptr::null_mut();
-    let mut io_config = esp_lcd_panel_io_spi_config_t::default();
-    io_config.cs_gpio_num = DISPLAY_CS_PIN;
-    io_config.dc_gpio_num = DISPLAY_DC_PIN;
-    io_config.spi_mode = 3;
-    io_config.pclk_hz = 40 * 1000 * 1000;
-    io_config.trans_queue_depth = 10;
-    io_config.lcd_cmd_bits = 8;
-    io_config.lcd_param_bits = 8;
-    esp!(unsafe {
-        esp_lcd_new_panel_io_spi(spi_host_device_t_SPI3_HOST as _, &io_config, &mut panel_io)
-    })?;
-
-    ::log::info!("Install LCD driver");
-    const DISPLAY_RST_PIN: i32 = 45;
-    let mut panel_config = esp_lcd_panel_dev_config_t::default();
-    let mut panel: esp_lcd_panel_handle_t = std::ptr::null_mut();
-
-    panel_config.reset_gpio_num = DISPLAY_RST_PIN;
-    panel_config.data_endian = lcd_rgb_data_endian_t_LCD_RGB_DATA_ENDIAN_LITTLE;
-    panel_config.__bindgen_anon_1.rgb_ele_order = lcd_rgb_element_order_t_LCD_RGB_ELEMENT_ORDER_RGB;
-    panel_config.bits_per_pixel = 16;
-
-    esp!(unsafe { esp_lcd_new_panel_st7789(panel_io, &panel_config, &mut panel) })?;
-    unsafe { ESP_LCD_PANEL_HANDLE = panel };
-
-    const DISPLAY_MIRROR_X: bool = true;
-    const DISPLAY_MIRROR_Y: bool = false;
-    const DISPLAY_SWAP_XY: bool = false;
-    const DISPLAY_INVERT_COLOR: bool = true;
-
-    ::log::info!("Reset LCD panel");
-    unsafe {
-        esp!(esp_lcd_panel_reset(panel))?;
-        esp!(esp_lcd_panel_init(panel))?;
-        esp!(esp_lcd_panel_invert_color(panel, DISPLAY_INVERT_COLOR))?;
-        esp!(esp_lcd_panel_swap_xy(panel, DISPLAY_SWAP_XY))?;
-        esp!(esp_lcd_panel_mirror(
-            panel,
-            DISPLAY_MIRROR_X,
-            DISPLAY_MIRROR_Y
-        ))?;
-        esp!(esp_lcd_panel_disp_on_off(panel, true))?; /* 启动屏幕 */
-    }
+pub type ColorFormat = Rgb565;
 
-    Ok(())
-}
+pub const DISPLAY_WIDTH: usize = crate::board::CONFIG.display.width;
+pub const DISPLAY_HEIGHT: usize = crate::board::CONFIG.display.height;
 
-#[cfg(feature = "boards")]
-pub fn lcd_init() -> Result<(), EspError> {
-    init_spi()?;
-    init_lcd()?;
-    Ok(())
-}
+type Buffer = Framebuffer<
+    ColorFormat,
+    RawU16,
+    LittleEndian,
+    DISPLAY_WIDTH,
+    DISPLAY_HEIGHT,
+    { buffer_size::<ColorFormat>(DISPLAY_WIDTH, DISPLAY_HEIGHT) },
+>;
 
-#[cfg(feature = "box")]
-pub fn lcd_init() -> Result<(), EspError> {
-    use esp_idf_svc::sys::hal_driver;
-    unsafe {
-        let config: hal_driver::lcd_cfg_t = std::mem::zeroed();
-        hal_driver::lcd_init(config);
-    }
-    Ok(())
-}
-
-#[inline(always)]
-fn get_esp_lcd_panel_handle() -> esp_idf_svc::sys::esp_lcd_panel_handle_t {
-    #[cfg(feature = "boards")]
-    unsafe {
-        ESP_LCD_PANEL_HANDLE
-    }
-    #[cfg(feature = "box")]
-    unsafe {
-        std::mem::transmute(esp_idf_svc::sys::hal_driver::panel_handle)
-    }
-}
+/// GIFs often give no frame delay, browsers show such frames for 100 ms.
+const DEFAULT_FRAME_DELAY: Duration = Duration::from_millis(100);
+/// Shorter delays are raised to this, flushing a frame takes a while.
+const MIN_FRAME_DELAY: Duration = Duration::from_millis(20);
 
 pub fn flush_display(color_data: &[u8], x_start: i32, y_start: i32, x_end: i32, y_end: i32) -> i32 {
     unsafe {
         let e = esp_idf_svc::sys::esp_lcd_panel_draw_bitmap(
-            get_esp_lcd_panel_handle(),
+            crate::board::panel_handle(),
             x_start,
             y_start,
             x_end,
@@ -185,7 +88,409 @@ pub fn backgroud(gif: &[u8]) -> Result<(), std::convert::Infallible> {
     Ok(())
 }
 
-const ALPHA: f32 = 0.5;
+fn parse_gif(gif: &[u8]) -> anyhow::Result<tinygif::Gif<'_, ColorFormat>> {
+    tinygif::Gif::from_slice(gif).map_err(|e| anyhow::anyhow!("Failed to parse GIF: {:?}", e))
+}
+
+/// A background GIF, played one frame per [`UI::tick`].
+struct Animation<'a> {
+    data: Cow<'a, [u8]>,
+    frames: usize,
+    next: usize,
+}
+
+impl<'a> Animation<'a> {
+    fn new(gif: Cow<'a, [u8]>) -> anyhow::Result<Self> {
+        let frames = parse_gif(&gif)?.frames().count();
+        if frames == 0 {
+            anyhow::bail!("GIF has no frames");
+        }
+        Ok(Self {
+            data: gif,
+            frames,
+            next: 0,
+        })
+    }
+
+    fn is_animated(&self) -> bool {
+        self.frames > 1
+    }
+
+    /// Draws the next frame over the previous ones and returns how long it
+    /// is shown.
+    fn draw_next<D>(&mut self, target: &mut D) -> Result<Duration, D::Error>
+    where
+        D: DrawTarget<Color = ColorFormat>,
+    {
+        let index = self.next;
+        self.next = (self.next + 1) % self.frames;
+        // Parsing only reads the headers, the frame is decoded while drawn.
+        let Ok(gif) = parse_gif(&self.data) else {
+            return Ok(DEFAULT_FRAME_DELAY);
+        };
+        let Some(frame) = gif.frames().nth(index) else {
+            return Ok(DEFAULT_FRAME_DELAY);
+        };
+        let mut bounded = Bounded {
+            target: &mut *target,
+            covered: None,
+        };
+        frame.draw(&mut bounded)?;
+        let covered = bounded.covered;
+        if !frame.is_transparent {
+            // An opaque frame replaces the screen, white outside of it. Only
+            // the outside is filled, so unchanged pixels stay clean.
+            fill_outside(target, covered, ColorFormat::WHITE)?;
+        }
+        Ok(match frame.delay_centis {
+            0 => DEFAULT_FRAME_DELAY,
+            centis => Duration::from_millis(centis as u64 * 10).max(MIN_FRAME_DELAY),
+        })
+    }
+}
+
+/// Passes drawing on and records the corners of the drawn area.
+struct Bounded<'a, D> {
+    target: &'a mut D,
+    covered: Option<(Point, Point)>,
+}
+
+impl<D: DrawTarget> Dimensions for Bounded<'_, D> {
+    fn bounding_box(&self) -> Rectangle {
+        self.target.bounding_box()
+    }
+}
+
+impl<D: DrawTarget> DrawTarget for Bounded<'_, D> {
+    type Color = D::Color;
+    type Error = D::Error;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        let covered = &mut self.covered;
+        let pixels = pixels.into_iter().inspect(|Pixel(point, _)| {
+            *covered = Some(match *covered {
+                Some((top_left, bottom_right)) => (
+                    top_left.component_min(*point),
+                    bottom_right.component_max(*point),
+                ),
+                None => (*point, *point),
+            });
+        });
+        self.target.draw_iter(pixels)
+    }
+}
+
+/// Fills `target` except for the rectangle between the `covered` corners.
+fn fill_outside<D: DrawTarget>(
+    target: &mut D,
+    covered: Option<(Point, Point)>,
+    color: D::Color,
+) -> Result<(), D::Error> {
+    let Some((top_left, bottom_right)) = covered else {
+        return target.clear(color);
+    };
+    let size = target.bounding_box().size;
+    let (w, h) = (size.width as i32, size.height as i32);
+    let (x0, y0) = (top_left.x.clamp(0, w), top_left.y.clamp(0, h));
+    let (x1, y1) = (
+        (bottom_right.x + 1).clamp(x0, w),
+        (bottom_right.y + 1).clamp(y0, h),
+    );
+    let strips = [
+        (0, 0, w, y0),
+        (0, y1, w, h - y1),
+        (0, y0, x0, y1 - y0),
+        (x1, y0, w - x1, y1 - y0),
+    ];
+    for (x, y, width, height) in strips {
+        if width > 0 && height > 0 {
+            let area = Rectangle::new(Point::new(x, y), Size::new(width as u32, height as u32));
+            target.fill_solid(&area, color)?;
+        }
+    }
+    Ok(())
+}
+
+/// Draws into a buffer and records the rows whose pixels changed.
+struct DirtyRows<'a> {
+    buffer: &'a mut Buffer,
+    rows: Option<(i32, i32)>,
+}
+
+impl DirtyRows<'_> {
+    fn area(&self) -> Option<Rectangle> {
+        self.rows.map(|(first, last)| {
+            Rectangle::new(
+                Point::new(0, first),
+                Size::new(DISPLAY_WIDTH as u32, (last - first + 1) as u32),
+            )
+        })
+    }
+}
+
+impl OriginDimensions for DirtyRows<'_> {
+    fn size(&self) -> Size {
+        self.buffer.size()
+    }
+}
+
+impl DrawTarget for DirtyRows<'_> {
+    type Color = ColorFormat;
+    type Error = std::convert::Infallible;
+
+    fn draw_iter<I>(&mut self, pixels: I) -> Result<(), Self::Error>
+    where
+        I: IntoIterator<Item = Pixel<Self::Color>>,
+    {
+        for Pixel(point, color) in pixels {
+            if self.buffer.pixel(point).is_some_and(|old| old != color) {
+                self.buffer.set_pixel(point, color);
+                self.rows = Some(match self.rows {
+                    Some((first, last)) => (first.min(point.y), last.max(point.y)),
+                    None => (point.y, point.y),
+                });
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Fill behind the state bar or the text, blended over the background.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Panel {
+    /// `#rrggbb`.
+    #[serde(with = "hex_color")]
+    pub color: ColorFormat,
+    /// In percent, 0 leaves only the background and 100 hides it.
+    pub opacity: u8,
+}
+
+impl Panel {
+    fn draw<D>(&self, area: Rectangle, background: &Buffer, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = ColorFormat>,
+    {
+        match self.opacity {
+            0 => Ok(()),
+            100.. => target.fill_solid(&area, self.color),
+            opacity => {
+                let alpha = opacity as f32 / 100.;
+                target.draw_iter(area.points().map(|p| {
+                    let under = background.pixel(p).unwrap_or(self.color);
+                    Pixel(p, alpha_mix(under, self.color, alpha))
+                }))
+            }
+        }
+    }
+}
+
+/// The panels of the overlay, a setting, see
+/// [`crate::settings::Settings::panels`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Panels {
+    pub state: Panel,
+    pub text: Panel,
+}
+
+impl Default for Panels {
+    fn default() -> Self {
+        Self {
+            state: Panel {
+                color: ColorFormat::CSS_DARK_BLUE,
+                opacity: 70,
+            },
+            text: Panel {
+                color: ColorFormat::CSS_BLACK,
+                opacity: 60,
+            },
+        }
+    }
+}
+
+impl Panels {
+    /// The panels of the light theme.
+    pub fn light() -> Self {
+        Self {
+            state: Panel {
+                color: ColorFormat::CSS_STEEL_BLUE,
+                opacity: 80,
+            },
+            text: Panel {
+                color: ColorFormat::WHITE,
+                opacity: 70,
+            },
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.state.opacity > 100 || self.text.opacity > 100 {
+            anyhow::bail!("The opacity must be 0 to 100 percent");
+        }
+        Ok(())
+    }
+}
+
+const ICON_HEIGHT: u32 = 12;
+const ICON_GAP: u32 = 4;
+const WIFI_WIDTH: u32 = 15;
+const SERVER_WIDTH: u32 = 8;
+const VOLUME_WIDTH: u32 = 14;
+const BATTERY_WIDTH: u32 = 18;
+
+/// What the icons at the right of the state bar show, see
+/// [`UI::set_status`].
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Status {
+    /// RSSI in dBm, `None` while the Wi-Fi is down.
+    pub wifi: Option<i8>,
+    pub server: bool,
+    /// Speaker volume in percent, 0 shows as muted. `None` hides the icon.
+    pub volume: Option<u8>,
+    /// Charge in percent, `None` hides the icon.
+    pub battery: Option<u8>,
+}
+
+/// Signal bars out of four.
+fn wifi_bars(rssi: Option<i8>) -> u32 {
+    match rssi {
+        None => 0,
+        Some(rssi) if rssi >= -55 => 4,
+        Some(rssi) if rssi >= -67 => 3,
+        Some(rssi) if rssi >= -78 => 2,
+        Some(_) => 1,
+    }
+}
+
+impl Status {
+    /// Room the icons take at most.
+    const WIDTH: u32 = WIFI_WIDTH + SERVER_WIDTH + VOLUME_WIDTH + BATTERY_WIDTH + 4 * ICON_GAP;
+
+    /// Draws the icons from the right edge of `area`, vertically centered.
+    fn draw<D>(&self, area: Rectangle, color: ColorFormat, target: &mut D) -> Result<(), D::Error>
+    where
+        D: DrawTarget<Color = ColorFormat>,
+    {
+        let fill = PrimitiveStyle::with_fill(color);
+        let stroke = PrimitiveStyle::with_stroke(color, 1);
+        let top = area.top_left.y + (area.size.height.saturating_sub(ICON_HEIGHT) / 2) as i32;
+        let mut x = area.top_left.x + (area.size.width - ICON_GAP) as i32;
+
+        if let Some(battery) = self.battery {
+            x -= BATTERY_WIDTH as i32;
+            let body = Rectangle::new(
+                Point::new(x, top + 2),
+                Size::new(BATTERY_WIDTH - 2, ICON_HEIGHT - 4),
+            );
+            body.into_styled(stroke).draw(target)?;
+            Rectangle::new(
+                Point::new(x + BATTERY_WIDTH as i32 - 2, top + 4),
+                Size::new(2, ICON_HEIGHT - 8),
+            )
+            .into_styled(fill)
+            .draw(target)?;
+            let charge = body.offset(-2);
+            let width = charge.size.width * battery.min(100) as u32 / 100;
+            Rectangle::new(charge.top_left, Size::new(width, charge.size.height))
+                .into_styled(fill)
+                .draw(target)?;
+            x -= ICON_GAP as i32;
+        }
+
+        if let Some(volume) = self.volume {
+            x -= VOLUME_WIDTH as i32;
+            let middle = top + ICON_HEIGHT as i32 / 2;
+            Rectangle::new(Point::new(x, middle - 2), Size::new(3, 4))
+                .into_styled(fill)
+                .draw(target)?;
+            Triangle::new(
+                Point::new(x + 2, middle),
+                Point::new(x + 6, top + 2),
+                Point::new(x + 6, top + ICON_HEIGHT as i32 - 2),
+            )
+            .into_styled(fill)
+            .draw(target)?;
+            if volume == 0 {
+                Line::new(
+                    Point::new(x + 8, middle - 3),
+                    Point::new(x + 13, middle + 2),
+                )
+                .into_styled(stroke)
+                .draw(target)?;
+                Line::new(
+                    Point::new(x + 8, middle + 2),
+                    Point::new(x + 13, middle - 3),
+                )
+                .into_styled(stroke)
+                .draw(target)?;
+            } else {
+                let bars = (volume as u32).div_ceil(34).min(3);
+                for i in 0..bars {
+                    let half = 2 + i as i32 * 2;
+                    let bar_x = x + 8 + 2 * i as i32;
+                    Line::new(
+                        Point::new(bar_x, middle - half),
+                        Point::new(bar_x, middle + half),
+                    )
+                    .into_styled(stroke)
+                    .draw(target)?;
+                }
+            }
+            x -= ICON_GAP as i32;
+        }
+
+        x -= SERVER_WIDTH as i32;
+        let dot = Circle::new(Point::new(x, top + 2), SERVER_WIDTH);
+        dot.into_styled(if self.server { fill } else { stroke })
+            .draw(target)?;
+        x -= ICON_GAP as i32;
+
+        x -= WIFI_WIDTH as i32;
+        let bars = wifi_bars(self.wifi);
+        for i in 0..4 {
+            let height = 3 * (i + 1);
+            Rectangle::new(
+                Point::new(x + 4 * i as i32, top + (ICON_HEIGHT - height) as i32),
+                Size::new(3, height),
+            )
+            .into_styled(if i < bars { fill } else { stroke })
+            .draw(target)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_color(s: &str) -> Option<ColorFormat> {
+    let hex = s.strip_prefix('#')?;
+    if hex.len() != 6 || !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+        return None;
+    }
+    let v = u32::from_str_radix(hex, 16).ok()?;
+    Some(Rgb888::new((v >> 16) as u8, (v >> 8) as u8, v as u8).into())
+}
+
+fn format_color(color: ColorFormat) -> String {
+    let c = Rgb888::from(color);
+    format!("#{:02x}{:02x}{:02x}", c.r(), c.g(), c.b())
+}
+
+/// Colors as `#rrggbb` strings.
+mod hex_color {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    use super::ColorFormat;
+
+    pub fn serialize<S: Serializer>(color: &ColorFormat, s: S) -> Result<S::Ok, S::Error> {
+        s.serialize_str(&super::format_color(*color))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<ColorFormat, D::Error> {
+        let s = String::deserialize(d)?;
+        super::parse_color(&s).ok_or_else(|| D::Error::custom(format!("Invalid color {:?}", s)))
+    }
+}
 
 // TextRenderer + CharacterStyle
 #[derive(Debug, Clone)]
@@ -263,98 +568,220 @@ impl CharacterStyle for MyTextStyle {
     }
 }
 
-pub struct UI {
-    pub state: String,
+/// Fonts a theme can pick. Each takes a lot of flash, so there are few.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Font {
+    Wqy12,
+    Wqy14,
+    Wqy16,
+}
+
+impl Font {
+    fn style(self, color: ColorFormat) -> U8g2TextStyle<ColorFormat> {
+        use u8g2_fonts::fonts::*;
+
+        match self {
+            Font::Wqy12 => U8g2TextStyle::new(u8g2_font_wqy12_t_gb2312a, color),
+            Font::Wqy14 => U8g2TextStyle::new(u8g2_font_wqy14_t_gb2312a, color),
+            Font::Wqy16 => U8g2TextStyle::new(u8g2_font_wqy16_t_gb2312, color),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TextAlign {
+    Left,
+    Center,
+    Right,
+    Justified,
+}
+
+impl From<TextAlign> for embedded_text::alignment::HorizontalAlignment {
+    fn from(align: TextAlign) -> Self {
+        match align {
+            TextAlign::Left => Self::Left,
+            TextAlign::Center => Self::Center,
+            TextAlign::Right => Self::Right,
+            TextAlign::Justified => Self::Justified,
+        }
+    }
+}
+
+/// Fonts, colors and layout of the overlay, a setting, see
+/// [`crate::settings::Settings::theme`]. Missing fields are taken from the
+/// dark theme, unknown ones are refused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Theme {
+    pub state_font: Font,
+    #[serde(with = "hex_color")]
+    pub state_color: ColorFormat,
+    pub text_font: Font,
+    #[serde(with = "hex_color")]
+    pub text_color: ColorFormat,
+    pub text_align: TextAlign,
+    /// Height of the state bar in pixels, the text takes the rest.
+    pub state_height: u32,
+    /// Space left and right of the text in pixels.
+    pub margin: u32,
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Self::dark()
+    }
+}
+
+impl Theme {
+    pub fn dark() -> Self {
+        Self {
+            state_font: Font::Wqy14,
+            state_color: ColorFormat::CSS_LIGHT_CYAN,
+            text_font: Font::Wqy16,
+            text_color: ColorFormat::CSS_WHEAT,
+            text_align: TextAlign::Center,
+            state_height: 32,
+            margin: 0,
+        }
+    }
+
+    pub fn light() -> Self {
+        Self {
+            state_color: ColorFormat::WHITE,
+            text_color: ColorFormat::CSS_DARK_SLATE_GRAY,
+            margin: 4,
+            ..Self::dark()
+        }
+    }
+
+    /// A built-in theme and the panels made for it.
+    pub fn named(name: &str) -> Option<(Self, Panels)> {
+        match name {
+            "dark" => Some((Self::dark(), Panels::default())),
+            "light" => Some((Self::light(), Panels::light())),
+            _ => None,
+        }
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if !(16..=DISPLAY_HEIGHT as u32 / 2).contains(&self.state_height) {
+            anyhow::bail!(
+                "The state bar height must be 16 to {} pixels",
+                DISPLAY_HEIGHT / 2
+            );
+        }
+        if self.margin > DISPLAY_WIDTH as u32 / 4 {
+            anyhow::bail!("The margin must be at most {} pixels", DISPLAY_WIDTH / 4);
+        }
+        Ok(())
+    }
+
+    fn text_style(&self) -> MyTextStyle {
+        MyTextStyle(self.text_font.style(self.text_color), 3)
+    }
+
+    /// Lines that do not fit in the text area are hidden, and scrolled into
+    /// view with [`UI::scroll_text`].
+    fn text_box_style(&self) -> embedded_text::style::TextBoxStyle {
+        embedded_text::style::TextBoxStyleBuilder::new()
+            .height_mode(embedded_text::style::HeightMode::Exact(
+                embedded_text::style::VerticalOverdraw::Hidden,
+            ))
+            .alignment(self.text_align.into())
+            .line_height(embedded_graphics::text::LineHeight::Percent(120))
+            .paragraph_spacing(16)
+            .build()
+    }
+}
+
+/// A theme as sent by the server or the setup page, the name of a built-in
+/// theme or a whole theme.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(untagged)]
+pub enum ThemeChoice {
+    Named(String),
+    Custom(Theme),
+}
+
+impl ThemeChoice {
+    /// The theme, with the panels of a built-in theme. A whole theme keeps
+    /// the panels as they are.
+    pub fn resolve(self) -> anyhow::Result<(Theme, Option<Panels>)> {
+        let (theme, panels) = match self {
+            ThemeChoice::Named(name) => {
+                let (theme, panels) = Theme::named(&name)
+                    .ok_or_else(|| anyhow::anyhow!("Unknown theme {:?}", name))?;
+                (theme, Some(panels))
+            }
+            ThemeChoice::Custom(theme) => (theme, None),
+        };
+        theme.validate()?;
+        Ok((theme, panels))
+    }
+}
+
+/// Where and how the overlay is drawn.
+struct Layout {
     state_area: Rectangle,
-    // state_background: Vec<Pixel<ColorFormat>>,
-    pub text: String,
     text_area: Rectangle,
-    // text_background: Vec<Pixel<ColorFormat>>,
-    display: Box<
-        Framebuffer<
-            ColorFormat,
-            RawU16,
-            LittleEndian,
-            DISPLAY_WIDTH,
-            DISPLAY_HEIGHT,
-            { buffer_size::<ColorFormat>(DISPLAY_WIDTH, DISPLAY_HEIGHT) },
-        >,
-    >,
+    theme: Theme,
+    panels: Panels,
 }
 
-const COLOR_WIDTH: u32 = 2;
+impl Layout {
+    fn new(theme: Theme, panels: Panels) -> Self {
+        let state_height = theme.state_height;
+        Self {
+            state_area: Rectangle::new(
+                Point::zero(),
+                Size::new(DISPLAY_WIDTH as u32, state_height),
+            ),
+            text_area: Rectangle::new(
+                Point::new(0, state_height as i32),
+                Size::new(DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32 - state_height),
+            ),
+            theme,
+            panels,
+        }
+    }
 
-// impl Default for UI {
-//     fn default() -> Self {
-//         let mut display = Box::new(Framebuffer::<
-//             ColorFormat,
-//             _,
-//             LittleEndian,
-//             DISPLAY_WIDTH,
-//             DISPLAY_HEIGHT,
-//             { buffer_size::<ColorFormat>(DISPLAY_WIDTH, DISPLAY_HEIGHT) },
-//         >::new());
-
-//         display.clear(ColorFormat::WHITE).unwrap();
-
-//         let state_area = Rectangle::new(
-//             display.bounding_box().top_left + Point::new(0, 0),
-//             Size::new(DISPLAY_WIDTH as u32, 32),
-//         );
-//         let text_area = Rectangle::new(
-//             display.bounding_box().top_left + Point::new(0, 32),
-//             Size::new(DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32 - 32),
-//         );
-
-//         let img = display.as_image();
-
-//         let state_pixels: Vec<Pixel<ColorFormat>> = state_area
-//             .into_styled(
-//                 PrimitiveStyleBuilder::new()
-//                     .stroke_color(ColorFormat::CSS_DARK_BLUE)
-//                     .stroke_width(1)
-//                     .fill_color(ColorFormat::CSS_DARK_BLUE)
-//                     .build(),
-//             )
-//             .pixels()
-//             .map(|p| {
-//                 if let Some(color) = img.pixel(p.0) {
-//                     Pixel(p.0, alpha_mix(color, p.1, ALPHA))
-//                 } else {
-//                     p
-//                 }
-//             })
-//             .collect();
-
-//         let box_pixels: Vec<Pixel<ColorFormat>> = text_area
-//             .into_styled(
-//                 PrimitiveStyleBuilder::new()
-//                     .stroke_color(ColorFormat::CSS_BLACK)
-//                     .stroke_width(5)
-//                     .fill_color(ColorFormat::CSS_BLACK)
-//                     .build(),
-//             )
-//             .pixels()
-//             .map(|p| {
-//                 if let Some(color) = img.pixel(p.0) {
-//                     Pixel(p.0, alpha_mix(color, p.1, ALPHA))
-//                 } else {
-//                     p
-//                 }
-//             })
-//             .collect();
-
-//         Self {
-//             state: String::new(),
-//             state_background: state_pixels,
-//             text: String::new(),
-//             text_background: box_pixels,
-//             display,
-//             state_area,
-//             text_area,
-//         }
-//     }
-// }
+    /// The state bar left of the status icons.
+    fn state_text_area(&self) -> Rectangle {
+        self.state_area.resized_width(
+            self.state_area.size.width - Status::WIDTH,
+            embedded_graphics::geometry::AnchorX::Left,
+        )
+    }
+
+    /// The text area without the margins.
+    fn text_bounds(&self) -> Rectangle {
+        self.text_area.resized_width(
+            self.text_area.size.width - 2 * self.theme.margin,
+            embedded_graphics::geometry::AnchorX::Center,
+        )
+    }
+}
+
+pub struct UI<'a> {
+    pub state: String,
+    pub text: String,
+    status: Status,
+    /// How many pixels of `text` are scrolled out above the text area.
+    text_offset: u32,
+    /// The text `text_offset` applies to.
+    shown_text: String,
+    layout: Layout,
+    /// The current background frame, without the overlay.
+    background: Box<Buffer>,
+    animation: Option<Animation<'a>>,
+    next_frame: Option<Instant>,
+    /// What the panel shows, the background with the overlay.
+    display: Box<Buffer>,
+}
+
+const COLOR_WIDTH: u32 = 2;
 
 fn alpha_mix(source: ColorFormat, target: ColorFormat, alpha: f32) -> ColorFormat {
     ColorFormat::new(
@@ -388,6 +815,72 @@ fn flush_area<const COLOR_WIDTH: u32>(data: &[u8], size: Size, area: Rectangle)
     }
 }
 
+/// What the overlay shows.
+struct Overlay<'a> {
+    state: &'a str,
+    status: &'a Status,
+    text: &'a str,
+    /// See [`UI::scroll_text`].
+    text_offset: u32,
+}
+
+/// Draws the state bar and the text within `clip`. Only the part of the
+/// text area that holds text gets a panel, the rest shows the background.
+fn draw_overlay<D>(
+    overlay: &Overlay,
+    layout: &Layout,
+    background: &Buffer,
+    clip: Rectangle,
+    target: &mut D,
+) -> Result<(), D::Error>
+where
+    D: DrawTarget<Color = ColorFormat>,
+{
+    let Overlay {
+        state,
+        status,
+        text,
+        text_offset,
+    } = *overlay;
+    let mut target = target.clipped(&clip);
+    let state_area = layout.state_area;
+    let text_area = layout.text_area;
+
+    let theme = &layout.theme;
+    let text_bounds = layout.text_bounds();
+
+    layout
+        .panels
+        .state
+        .draw(state_area.intersection(&clip), background, &mut target)?;
+
+    Text::with_alignment(
+        state,
+        layout.state_text_area().center(),
+        theme.state_font.style(theme.state_color),
+        Alignment::Center,
+    )
+    .draw(&mut target)?;
+    status.draw(state_area, theme.state_color, &mut target)?;
+
+    let textbox_style = theme.text_box_style();
+    let mut text_box =
+        TextBox::with_textbox_style(text, text_bounds, theme.text_style(), textbox_style);
+    text_box.set_vertical_offset(-(text_offset as i32));
+    if !text.is_empty() {
+        // Room for the glyphs moved down by `MyTextStyle`.
+        let height =
+            textbox_style.measure_text_height(&theme.text_style(), text, text_bounds.size.width)
+                + 8;
+        let panel = Rectangle::new(text_area.top_left, Size::new(text_area.size.width, height))
+            .intersection(&text_area)
+            .intersection(&clip);
+        layout.panels.text.draw(panel, background, &mut target)?;
+    }
+    text_box.draw(&mut target)?;
+    Ok(())
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct QrPixel(ColorFormat);
 
@@ -441,181 +934,184 @@ impl qrcode::render::Canvas for QrCanvas {
     }
 }
 
-impl UI {
-    pub fn new(backgroud_gif: Option<&[u8]>) -> anyhow::Result<Self> {
+impl<'a> UI<'a> {
+    /// Borrows the background GIF where it outlives the UI, and takes a
+    /// freshly received one.
+    pub fn new(backgroud_gif: Option<Cow<'a, [u8]>>) -> anyhow::Result<Self> {
         log::info!("Framebuffer creating");
-        let mut display = Box::new(Framebuffer::<
-            ColorFormat,
-            _,
-            LittleEndian,
-            DISPLAY_WIDTH,
-            DISPLAY_HEIGHT,
-            { buffer_size::<ColorFormat>(DISPLAY_WIDTH, DISPLAY_HEIGHT) },
-        >::new());
+        let mut background = Box::new(Buffer::new());
+        let mut display = Box::new(Buffer::new());
         log::info!("Framebuffer created");
 
         log::info!("Display creating");
+        background.clear(ColorFormat::WHITE).unwrap();
         display.clear(ColorFormat::WHITE).unwrap();
         log::info!("Display cleared");
 
-        log::info!("State area creating");
-        let state_area = Rectangle::new(
-            display.bounding_box().top_left + Point::new(0, 0),
-            Size::new(DISPLAY_WIDTH as u32, 32),
-        );
-        log::info!("State area created");
-
-        log::info!("Text area creating");
-        let text_area = Rectangle::new(
-            display.bounding_box().top_left + Point::new(0, 32),
-            Size::new(DISPLAY_WIDTH as u32, DISPLAY_HEIGHT as u32 - 32),
-        );
-        log::info!("Text area created");
-
         log::info!("background creating");
-        if let Some(gif) = backgroud_gif {
-            let image = tinygif::Gif::<ColorFormat>::from_slice(gif)
-                .map_err(|e| anyhow::anyhow!("Failed to parse GIF: {:?}", e))?;
-            for frame in image.frames() {
-                frame.draw(display.as_mut()).unwrap();
+        let mut next_frame = None;
+        let animation = match backgroud_gif {
+            Some(gif) => {
+                let mut animation = Animation::new(gif)?;
+                let delay = animation.draw_next(background.as_mut()).unwrap();
+                if animation.is_animated() {
+                    next_frame = Some(Instant::now() + delay);
+                }
+                Some(animation)
             }
-        }
+            None => None,
+        };
         log::info!("background created");
 
-        // log::info!("Image creating");
-        // let img = display.as_image();
-        // log::info!("Image created");
-
-        // log::info!("State pixels creating");
-        // let state_pixels: Vec<Pixel<ColorFormat>> = state_area
-        //     .into_styled(
-        //         PrimitiveStyleBuilder::new()
-        //             .stroke_color(ColorFormat::CSS_DARK_BLUE)
-        //             .stroke_width(1)
-        //             .fill_color(ColorFormat::CSS_DARK_BLUE)
-        //             .build(),
-        //     )
-        //     .pixels()
-        //     .map(|p| {
-        //         if let Some(color) = img.pixel(p.0) {
-        //             Pixel(p.0, alpha_mix(color, p.1, ALPHA))
-        //         } else {
-        //             p
-        //         }
-        //     })
-        //     .collect();
-        // log::info!("State pixels created");
-
-        // log::info!("Text pixels creating");
-        // let box_pixels: Vec<Pixel<ColorFormat>> = text_area
-        //     .into_styled(
-        //         PrimitiveStyleBuilder::new()
-        //             .stroke_color(ColorFormat::CSS_BLACK)
-        //             .stroke_width(5)
-        //             .fill_color(ColorFormat::CSS_BLACK)
-        //             .build(),
-        //     )
-        //     .pixels()
-        //     .map(|p| {
-        //         if let Some(color) = img.pixel(p.0) {
-        //             Pixel(p.0, alpha_mix(color, p.1, ALPHA))
-        //         } else {
-        //             p
-        //         }
-        //     })
-        //     .collect();
-        // log::info!("Text pixels created");
-
         Ok(Self {
             state: String::new(),
-            // state_background: state_pixels,
             text: String::new(),
-            // text_background: box_pixels,
+            status: Status::default(),
+            text_offset: 0,
+            shown_text: String::new(),
+            layout: Layout::new(Theme::default(), Panels::default()),
+            background,
+            animation,
+            next_frame,
             display,
-            state_area,
-            text_area,
         })
     }
 
-    pub fn display_flush(&mut self) -> anyhow::Result<()> {
-        log::info!("Display flush");
-        // self.state_background
-        //     .iter()
-        //     .cloned()
-        //     .draw(self.display.as_mut())?;
-        // self.text_background
-        //     .iter()
-        //     .cloned()
-        //     .draw(self.display.as_mut())?;
-
-        self.state_area
-            .into_styled(
-                PrimitiveStyleBuilder::new()
-                    .stroke_color(ColorFormat::CSS_DARK_BLUE)
-                    .stroke_width(1)
-                    .fill_color(ColorFormat::CSS_DARK_BLUE)
-                    .build(),
-            )
-            .draw(self.display.as_mut())?;
+    /// Takes effect with the next [`UI::display_flush`].
+    pub fn set_theme(&mut self, theme: Theme) {
+        self.layout = Layout::new(theme, self.layout.panels);
+        self.text_offset = 0;
+    }
 
-        self.text_area
-            .into_styled(
-                PrimitiveStyleBuilder::new()
-                    .stroke_color(ColorFormat::CSS_BLACK)
-                    .stroke_width(5)
-                    .fill_color(ColorFormat::CSS_BLACK)
-                    .build(),
-            )
-            .draw(self.display.as_mut())?;
+    pub fn set_panels(&mut self, panels: Panels) {
+        self.layout.panels = panels;
+    }
 
-        Text::with_alignment(
-            &self.state,
-            self.state_area.center(),
-            U8g2TextStyle::new(
-                u8g2_fonts::fonts::u8g2_font_wqy14_t_gb2312a,
-                ColorFormat::CSS_LIGHT_CYAN,
-            ),
-            Alignment::Center,
-        )
-        .draw(self.display.as_mut())?;
+    /// When [`UI::tick`] should show the next background frame, `None` for
+    /// a still background.
+    pub fn next_frame(&self) -> Option<Instant> {
+        self.next_frame
+    }
 
-        let textbox_style = embedded_text::style::TextBoxStyleBuilder::new()
-            .height_mode(embedded_text::style::HeightMode::FitToText)
-            .alignment(embedded_text::alignment::HorizontalAlignment::Center)
-            .line_height(embedded_graphics::text::LineHeight::Percent(120))
-            .paragraph_spacing(16)
-            .build();
-        let text_box = TextBox::with_textbox_style(
+    /// Shows the next background frame, redrawing only the rows it changed.
+    pub fn tick(&mut self) -> anyhow::Result<()> {
+        let Some(animation) = &mut self.animation else {
+            self.next_frame = None;
+            return Ok(());
+        };
+        let mut target = DirtyRows {
+            buffer: self.background.as_mut(),
+            rows: None,
+        };
+        let delay = animation.draw_next(&mut target)?;
+        let dirty = target.area();
+        self.next_frame = Some(Instant::now() + delay);
+        if let Some(area) = dirty {
+            self.compose(area)?;
+            self.flush(area);
+        }
+        Ok(())
+    }
+
+    /// Redraws the state bar with the new icons, if they changed.
+    pub fn set_status(&mut self, status: Status) -> anyhow::Result<()> {
+        if status != self.status {
+            self.status = status;
+            let area = self.layout.state_area;
+            self.compose(area)?;
+            self.flush(area);
+        }
+        Ok(())
+    }
+
+    /// Scrolls the text so that `position`, from 0 for its start to 1 for
+    /// its end, is in view. Moves by whole lines and redraws only the text
+    /// area, and only when the text changed or moved.
+    pub fn scroll_text(&mut self, position: f32) -> anyhow::Result<()> {
+        let changed = self.text != self.shown_text;
+        if changed {
+            self.shown_text.clone_from(&self.text);
+        }
+        let text_area = self.layout.text_area;
+        let offset = self.text_offset_at(position);
+        if changed || offset != self.text_offset {
+            self.text_offset = offset;
+            self.compose(text_area)?;
+            self.flush(text_area);
+        }
+        Ok(())
+    }
+
+    /// The scroll offset that brings `position` of the text into view.
+    fn text_offset_at(&self, position: f32) -> u32 {
+        let text_area = self.layout.text_area;
+        let theme = &self.layout.theme;
+        let height = theme.text_box_style().measure_text_height(
+            &theme.text_style(),
             &self.text,
-            self.text_area,
-            MyTextStyle(
-                U8g2TextStyle::new(
-                    u8g2_fonts::fonts::u8g2_font_wqy16_t_gb2312,
-                    ColorFormat::CSS_WHEAT,
-                ),
-                3,
-            ),
-            textbox_style,
-        );
-        text_box.draw(self.display.as_mut())?;
+            self.layout.text_bounds().size.width,
+        ) + 8;
+        let max = height.saturating_sub(text_area.size.height);
+        let line = theme.text_style().line_height() * 6 / 5;
+        if position >= 1.0 {
+            max
+        } else {
+            ((max as f32 * position.max(0.0)) as u32 / line * line).min(max)
+        }
+    }
 
+    /// Redraws the whole rows of `area` from the background and the overlay.
+    fn compose(&mut self, area: Rectangle) -> anyhow::Result<()> {
+        let row_len = DISPLAY_WIDTH * COLOR_WIDTH as usize;
+        let start = area.top_left.y as usize * row_len;
+        let end = start + area.size.height as usize * row_len;
+        self.display.data_mut()[start..end].copy_from_slice(&self.background.data()[start..end]);
+        let overlay = Overlay {
+            state: &self.state,
+            status: &self.status,
+            text: &self.text,
+            text_offset: self.text_offset,
+        };
+        draw_overlay(
+            &overlay,
+            &self.layout,
+            &self.background,
+            area,
+            self.display.as_mut(),
+        )?;
+        Ok(())
+    }
+
+    fn flush(&self, area: Rectangle) {
         for i in 0..5 {
-            let e = flush_area::<COLOR_WIDTH>(
-                self.display.data(),
-                self.display.size(),
-                Rectangle::new(
-                    self.state_area.top_left,
-                    Size::new(
-                        self.text_area.size.width,
-                        self.text_area.size.height + self.state_area.size.height,
-                    ),
-                ),
-            );
+            let e = flush_area::<COLOR_WIDTH>(self.display.data(), self.display.size(), area);
             if e == 0 {
                 break;
             }
             log::warn!("flush_display error: {} retry {i}", e);
         }
+    }
+
+    pub fn display_flush(&mut self) -> anyhow::Result<()> {
+        log::info!("Display flush");
+        if self.text != self.shown_text {
+            self.shown_text.clone_from(&self.text);
+            self.text_offset = 0;
+        }
+        let area = self.display.bounding_box();
+        self.compose(area)?;
+        self.flush(area);
+        Ok(())
+    }
+
+    /// Like [`UI::display_flush`], with the text already scrolled to
+    /// `position` as by [`UI::scroll_text`], in a single flush.
+    pub fn display_flush_scrolled(&mut self, position: f32) -> anyhow::Result<()> {
+        self.shown_text.clone_from(&self.text);
+        self.text_offset = self.text_offset_at(position);
+        let area = self.display.bounding_box();
+        self.compose(area)?;
+        self.flush(area);
         Ok(())
     }
 
@@ -627,41 +1123,34 @@ impl UI {
             .module_dimensions(4, 4)
             .build();
 
-        // self.state_background
-        //     .iter()
-        //     .cloned()
-        //     .draw(self.display.as_mut())?;
-        // self.text_background
-        //     .iter()
-        //     .cloned()
-        //     .draw(self.display.as_mut())?;
-
-        self.state_area
+        self.layout
+            .state_area
             .into_styled(
                 PrimitiveStyleBuilder::new()
-                    .stroke_color(ColorFormat::CSS_DARK_BLUE)
+                    .stroke_color(self.layout.panels.state.color)
                     .stroke_width(1)
-                    .fill_color(ColorFormat::CSS_DARK_BLUE)
+                    .fill_color(self.layout.panels.state.color)
                     .build(),
             )
             .draw(self.display.as_mut())?;
 
-        self.text_area
+        self.layout
+            .text_area
             .into_styled(
                 PrimitiveStyleBuilder::new()
-                    .stroke_color(ColorFormat::CSS_BLACK)
+                    .stroke_color(self.layout.panels.text.color)
                     .stroke_width(5)
-                    .fill_color(ColorFormat::CSS_BLACK)
+                    .fill_color(self.layout.panels.text.color)
                     .build(),
             )
             .draw(self.display.as_mut())?;
 
         self.display
             .cropped(&Rectangle::new(
-                self.text_area.top_left
+                self.layout.text_area.top_left
                     + Point::new(
-                        ((self.text_area.size.width - width) / 2) as i32,
-                        (self.text_area.size.height - height) as i32,
+                        ((self.layout.text_area.size.width - width) / 2) as i32,
+                        (self.layout.text_area.size.height - height) as i32,
                     ),
                 Size::new(width, height),
             ))
@@ -669,11 +1158,8 @@ impl UI {
 
         Text::with_alignment(
             &self.state,
-            self.state_area.center(),
-            U8g2TextStyle::new(
-                u8g2_fonts::fonts::u8g2_font_wqy12_t_gb2312a,
-                ColorFormat::CSS_LIGHT_CYAN,
-            ),
+            self.layout.state_area.center(),
+            Font::Wqy12.style(self.layout.theme.state_color),
             Alignment::Center,
         )
         .draw(self.display.as_mut())?;
@@ -686,14 +1172,8 @@ impl UI {
             .build();
         let text_box = TextBox::with_textbox_style(
             &self.text,
-            self.text_area,
-            MyTextStyle(
-                U8g2TextStyle::new(
-                    u8g2_fonts::fonts::u8g2_font_wqy12_t_gb2312a,
-                    ColorFormat::CSS_WHEAT,
-                ),
-                3,
-            ),
+            self.layout.text_area,
+            MyTextStyle(Font::Wqy12.style(self.layout.theme.text_color), 3),
             textbox_style,
         );
         text_box.draw(self.display.as_mut())?;
@@ -703,10 +1183,10 @@ impl UI {
                 self.display.data(),
                 self.display.size(),
                 Rectangle::new(
-                    self.state_area.top_left,
+                    self.layout.state_area.top_left,
                     Size::new(
-                        self.text_area.size.width,
-                        self.text_area.size.height + self.state_area.size.height,
+                        self.layout.text_area.size.width,
+                        self.layout.text_area.size.height + self.layout.state_area.size.height,
                     ),
                 ),
             );
@@ -718,3 +1198,70 @@ impl UI {
         Ok(())
     }
 }
+
+#[test]
+fn test_color() {
+    assert_eq!(parse_color("#ffffff"), Some(ColorFormat::WHITE));
+    assert_eq!(parse_color("#000000"), Some(ColorFormat::BLACK));
+    assert_eq!(format_color(ColorFormat::WHITE), "#ffffff");
+    assert_eq!(parse_color("ffffff"), None);
+    assert_eq!(parse_color("#+fffff"), None);
+    assert_eq!(parse_color("#fff"), None);
+
+    let theme: Theme =
+        serde_json::from_str(&serde_json::to_string(&Theme::light()).unwrap()).unwrap();
+    assert_eq!(theme, Theme::light());
+}
+
+#[test]
+fn test_theme_choice() {
+    let theme = serde_json::from_str::<ThemeChoice>(r#""light""#).unwrap();
+    assert_eq!(
+        theme.resolve().unwrap(),
+        (Theme::light(), Some(Panels::light()))
+    );
+    let theme = serde_json::from_str::<ThemeChoice>(r#"{"margin":8}"#).unwrap();
+    let (theme, panels) = theme.resolve().unwrap();
+    assert_eq!(theme.state_height, 32);
+    assert_eq!(panels, None);
+    assert!(serde_json::from_str::<ThemeChoice>(r#"{"margn":8}"#).is_err());
+    assert!(serde_json::from_str::<ThemeChoice>(r#""neon""#)
+        .unwrap()
+        .resolve()
+        .is_err());
+}
+
+#[test]
+fn test_opaque_frames() {
+    // Two identical opaque 1x1 frames, black on a white screen.
+    const FRAME: &[u8] =
+        b"!\xf9\x04\x00\x0a\x00\x00\x00,\x00\x00\x00\x00\x01\x00\x01\x00\x00\x02\x02D\x01\x00";
+    let header: &[u8] = b"GIF89a\x01\x00\x01\x00\x80\x00\x00\x00\x00\x00\xff\xff\xff";
+    let gif = [header, FRAME, FRAME, b";".as_slice()].concat();
+    let mut animation = Animation::new(Cow::Borrowed(&gif)).unwrap();
+    let mut buffer = Box::new(Buffer::new());
+
+    let mut target = DirtyRows {
+        buffer: buffer.as_mut(),
+        rows: None,
+    };
+    animation.draw_next(&mut target).unwrap();
+    assert!(target.area().is_some());
+    assert_eq!(buffer.pixel(Point::new(0, 0)), Some(ColorFormat::BLACK));
+    assert_eq!(buffer.pixel(Point::new(1, 1)), Some(ColorFormat::WHITE));
+
+    let mut target = DirtyRows {
+        buffer: buffer.as_mut(),
+        rows: None,
+    };
+    animation.draw_next(&mut target).unwrap();
+    assert!(target.area().is_none());
+}
+
+#[test]
+fn test_wifi_bars() {
+    assert_eq!(wifi_bars(None), 0);
+    assert_eq!(wifi_bars(Some(-40)), 4);
+    assert_eq!(wifi_bars(Some(-70)), 2);
+    assert_eq!(wifi_bars(Some(-90)), 1);
+}
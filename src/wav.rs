@@ -0,0 +1,158 @@
+use std::borrow::Cow;
+use std::io::Write;
+
+pub const HEADER_LEN: usize = 44;
+
+/// Writes a canonical 44-byte RIFF/WAVE header for PCM data of `data_len` bytes.
+pub fn write_header<W: Write>(
+    w: &mut W,
+    sample_rate: u32,
+    channels: u16,
+    bits_per_sample: u16,
+    data_len: u32,
+) -> std::io::Result<()> {
+    let block_align = channels * bits_per_sample / 8;
+    let byte_rate = sample_rate * block_align as u32;
+
+    w.write_all(b"RIFF")?;
+    w.write_all(&(HEADER_LEN as u32 - 8 + data_len).to_le_bytes())?;
+    w.write_all(b"WAVE")?;
+
+    w.write_all(b"fmt ")?;
+    w.write_all(&16u32.to_le_bytes())?;
+    w.write_all(&1u16.to_le_bytes())?; // PCM
+    w.write_all(&channels.to_le_bytes())?;
+    w.write_all(&sample_rate.to_le_bytes())?;
+    w.write_all(&byte_rate.to_le_bytes())?;
+    w.write_all(&block_align.to_le_bytes())?;
+    w.write_all(&bits_per_sample.to_le_bytes())?;
+
+    w.write_all(b"data")?;
+    w.write_all(&data_len.to_le_bytes())?;
+    Ok(())
+}
+
+/// A parsed RIFF/WAVE file borrowing its PCM samples from the input.
+#[derive(Debug, Clone, Copy)]
+pub struct Wav<'a> {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub bits_per_sample: u16,
+    pub data: &'a [u8],
+}
+
+const FORMAT_PCM: u16 = 1;
+const FORMAT_EXTENSIBLE: u16 = 0xFFFE;
+
+/// Walks the RIFF chunks of `buf`, validating the `fmt ` chunk and locating `data`.
+pub fn parse(buf: &[u8]) -> anyhow::Result<Wav<'_>> {
+    if buf.len() < 12 || &buf[0..4] != b"RIFF" || &buf[8..12] != b"WAVE" {
+        anyhow::bail!("Not a RIFF/WAVE file");
+    }
+
+    let mut fmt = None;
+    let mut offset = 12;
+    while offset + 8 <= buf.len() {
+        let id = &buf[offset..offset + 4];
+        let size = u32::from_le_bytes(buf[offset + 4..offset + 8].try_into().unwrap()) as usize;
+        let body_start = offset + 8;
+        let body_end = body_start.saturating_add(size);
+
+        match id {
+            b"fmt " => {
+                let body = buf
+                    .get(body_start..body_end)
+                    .filter(|b| b.len() >= 16)
+                    .ok_or_else(|| anyhow::anyhow!("Truncated fmt chunk"))?;
+                let format = u16::from_le_bytes([body[0], body[1]]);
+                let channels = u16::from_le_bytes([body[2], body[3]]);
+                let sample_rate = u32::from_le_bytes(body[4..8].try_into().unwrap());
+                let bits_per_sample = u16::from_le_bytes([body[14], body[15]]);
+                if format != FORMAT_PCM && format != FORMAT_EXTENSIBLE {
+                    anyhow::bail!("Unsupported WAV format tag {format}, only PCM is supported");
+                }
+                fmt = Some((sample_rate, channels, bits_per_sample));
+            }
+            b"data" => {
+                let (sample_rate, channels, bits_per_sample) =
+                    fmt.ok_or_else(|| anyhow::anyhow!("data chunk before fmt chunk"))?;
+                // Streams written on the fly may leave the size unset, take what is there.
+                let data = &buf[body_start..body_end.min(buf.len())];
+                return Ok(Wav {
+                    sample_rate,
+                    channels,
+                    bits_per_sample,
+                    data,
+                });
+            }
+            _ => {}
+        }
+
+        // Chunks are padded to an even size.
+        offset = body_end.saturating_add(size & 1);
+    }
+
+    anyhow::bail!("Missing data chunk")
+}
+
+impl<'a> Wav<'a> {
+    /// Returns the samples as 16-bit mono PCM at `sample_rate`, downmixing
+    /// stereo input and resampling other rates. Other bit depths are rejected.
+    pub fn to_pcm16_mono(self, sample_rate: u32) -> anyhow::Result<Cow<'a, [u8]>> {
+        if self.bits_per_sample != 16 {
+            anyhow::bail!(
+                "Unsupported bit depth {}, expected 16 bits",
+                self.bits_per_sample
+            );
+        }
+        if !crate::resample::is_supported_rate(self.sample_rate) {
+            anyhow::bail!("Unsupported sample rate {}", self.sample_rate);
+        }
+
+        let pcm = match self.channels {
+            1 => Cow::Borrowed(&self.data[..self.data.len() & !1]),
+            2 => {
+                let mut pcm = Vec::with_capacity(self.data.len() / 2);
+                for frame in self.data.chunks_exact(4) {
+                    let l = i16::from_le_bytes([frame[0], frame[1]]) as i32;
+                    let r = i16::from_le_bytes([frame[2], frame[3]]) as i32;
+                    pcm.extend_from_slice(&(((l + r) / 2) as i16).to_le_bytes());
+                }
+                Cow::Owned(pcm)
+            }
+            n => anyhow::bail!("Unsupported channel count {n}"),
+        };
+
+        if self.sample_rate == sample_rate {
+            Ok(pcm)
+        } else {
+            let mut resampler = crate::resample::Resampler::new(self.sample_rate, sample_rate);
+            Ok(Cow::Owned(resampler.process(&pcm)))
+        }
+    }
+}
+
+#[test]
+fn test_parse_wav() {
+    let samples = [1i16, -1, 1000, -1000];
+    let mut data = vec![];
+    for (l, r) in samples.iter().zip(samples.iter().rev()) {
+        data.extend_from_slice(&l.to_le_bytes());
+        data.extend_from_slice(&r.to_le_bytes());
+    }
+
+    let mut buf = vec![];
+    write_header(&mut buf, 16000, 2, 16, data.len() as u32).unwrap();
+    buf.extend_from_slice(&data);
+
+    let wav = parse(&buf).unwrap();
+    assert_eq!(wav.sample_rate, 16000);
+    assert_eq!(wav.channels, 2);
+    assert_eq!(wav.data.len(), data.len());
+
+    let pcm = wav.to_pcm16_mono(16000).unwrap();
+    assert_eq!(pcm.len(), data.len() / 2);
+    let pcm = wav.to_pcm16_mono(8000).unwrap();
+    assert_eq!(pcm.len(), data.len() / 4);
+    assert!(parse(&data).is_err());
+}
@@ -1,23 +1,116 @@
+use esp_idf_svc::hal::modem::Modem;
+use esp_idf_svc::hal::peripheral::Peripheral;
+use esp_idf_svc::wifi::AccessPointInfo;
+use esp_idf_svc::wifi::AuthMethod;
 use esp_idf_svc::wifi::ClientConfiguration;
 use esp_idf_svc::wifi::Configuration;
 use esp_idf_svc::wifi::WifiDriver;
 
-pub fn scan(
-    modem: esp_idf_svc::hal::modem::Modem,
+pub fn scan<'d>(
+    modem: impl Peripheral<P = Modem> + 'd,
     sysloop: esp_idf_svc::eventloop::EspSystemEventLoop,
-) {
+) -> anyhow::Result<Vec<AccessPointInfo>> {
     log::info!("Starting WiFi scan...");
-    let mut wifi_driver = WifiDriver::new(modem, sysloop, None).unwrap();
-    wifi_driver
-        .set_configuration(&Configuration::Client(ClientConfiguration::default()))
-        .unwrap();
-    wifi_driver.start().unwrap();
+    let mut wifi_driver = WifiDriver::new(modem, sysloop, None)?;
+    wifi_driver.set_configuration(&Configuration::Client(ClientConfiguration::default()))?;
+    wifi_driver.start()?;
 
     log::info!("Scanning for WiFi networks...");
-    let res = wifi_driver.scan().unwrap();
+    let res = wifi_driver.scan()?;
     log::info!("Scan complete. Found {} networks.", res.len());
-    for network in res {
+    for network in &res {
         log::info!("Found network: {:?}", network);
     }
     log::info!("WiFi scan finished.");
+    Ok(res)
+}
+
+pub fn auth_name(auth: Option<AuthMethod>) -> &'static str {
+    match auth {
+        None | Some(AuthMethod::None) => "open",
+        Some(AuthMethod::WEP) => "wep",
+        Some(AuthMethod::WPA) => "wpa",
+        Some(AuthMethod::WPA2Personal) => "wpa2",
+        Some(AuthMethod::WPAWPA2Personal) => "wpa/wpa2",
+        Some(AuthMethod::WPA2Enterprise) => "wpa2-enterprise",
+        Some(AuthMethod::WPA3Personal) => "wpa3",
+        Some(AuthMethod::WPA2WPA3Personal) => "wpa2/wpa3",
+        Some(_) => "other",
+    }
+}
+
+#[derive(serde::Serialize)]
+struct Network<'a> {
+    ssid: &'a str,
+    rssi: i8,
+    auth: &'static str,
+}
+
+/// Scan results as a JSON list of `{"ssid", "rssi", "auth"}`, strongest
+/// first, one entry per SSID, hidden networks left out. Entries are dropped
+/// from the end until the list fits in `max_len` bytes.
+pub fn to_json(networks: &[AccessPointInfo], max_len: usize) -> String {
+    let networks = networks
+        .iter()
+        .map(|n| Network {
+            ssid: n.ssid.as_str(),
+            rssi: n.signal_strength,
+            auth: auth_name(n.auth_method),
+        })
+        .collect();
+    list_json(networks, max_len)
+}
+
+fn list_json(mut networks: Vec<Network>, max_len: usize) -> String {
+    networks.retain(|n| !n.ssid.is_empty());
+    networks.sort_by(|a, b| b.rssi.cmp(&a.rssi));
+    let mut seen = std::collections::HashSet::new();
+    networks.retain(|n| seen.insert(n.ssid));
+
+    let mut json = String::from("[");
+    for network in networks {
+        let entry = serde_json::to_string(&network).unwrap();
+        let comma = if json.len() > 1 { 1 } else { 0 };
+        // Room for the closing bracket.
+        if json.len() + comma + entry.len() + 1 > max_len {
+            break;
+        }
+        if comma == 1 {
+            json.push(',');
+        }
+        json.push_str(&entry);
+    }
+    json.push(']');
+    json
+}
+
+#[test]
+fn test_list_json() {
+    let network = |ssid, rssi| Network {
+        ssid,
+        rssi,
+        auth: "wpa2",
+    };
+    let networks = || {
+        vec![
+            network("home", -70),
+            network("", -30),
+            network("office", -50),
+            network("home", -40),
+        ]
+    };
+    let json = list_json(networks(), 4096);
+    assert_eq!(
+        json,
+        r#"[{"ssid":"home","rssi":-40,"auth":"wpa2"},{"ssid":"office","rssi":-50,"auth":"wpa2"}]"#
+    );
+    let list: Vec<serde_json::Value> = serde_json::from_str(&json).unwrap();
+    assert_eq!(list.len(), 2);
+
+    assert_eq!(list_json(networks(), json.len()), json);
+    assert_eq!(
+        list_json(networks(), json.len() - 1),
+        r#"[{"ssid":"home","rssi":-40,"auth":"wpa2"}]"#
+    );
+    assert_eq!(list_json(networks(), 10), "[]");
 }
@@ -27,6 +27,14 @@ impl Server {
         Ok(Self { uri, timeout, ws })
     }
 
+    /// Replaces the connection with a new one to the same URI.
+    pub async fn reconnect(&mut self) -> anyhow::Result<()> {
+        let timeout = self.timeout;
+        *self = Self::new(self.uri.clone()).await?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
     pub fn set_timeout(&mut self, timeout: std::time::Duration) {
         self.timeout = timeout;
     }